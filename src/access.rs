@@ -0,0 +1,299 @@
+use crate::rate::TokenBucket;
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl AccessList {
+    /// Returns a description of the rule that denies `ip`, or `None` if it's permitted.
+    /// Deny rules take precedence; a non-empty allow list must also match.
+    pub fn check(&self, ip: IpAddr) -> Option<String> {
+        let ip = ip.to_canonical();
+
+        if let Some(net) = self.deny.iter().find(|net| net.contains(&ip)) {
+            return Some(format!("deny {}", net));
+        }
+
+        if !self.allow.is_empty() && !self.allow.iter().any(|net| net.contains(&ip)) {
+            return Some("not in allow list".to_string());
+        }
+
+        None
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+}
+
+/// Parses a CIDR range, accepting bare addresses as single-host ranges.
+pub fn parse_net(s: &str) -> Result<IpNet, String> {
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("invalid CIDR or IP address: {}", s))
+}
+
+#[derive(Debug, Default)]
+struct FailureState {
+    failures: Vec<Instant>,
+    banned_until: Option<Instant>,
+}
+
+/// Temporarily bans IPs that fail authentication too often.
+#[derive(Debug)]
+pub struct BanList {
+    threshold: usize,
+    window: Duration,
+    duration: Duration,
+    state: Mutex<HashMap<IpAddr, FailureState>>,
+}
+
+impl BanList {
+    pub fn new(threshold: usize, window: Duration, duration: Duration) -> Self {
+        Self {
+            threshold,
+            window,
+            duration,
+            state: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        self.prune(&mut state, now);
+        state
+            .get(&ip)
+            .and_then(|s| s.banned_until)
+            .is_some_and(|until| until > now)
+    }
+
+    pub fn record_failure(&self, ip: IpAddr) {
+        let ip = ip.to_canonical();
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        self.prune(&mut state, now);
+
+        let entry = state.entry(ip).or_default();
+        if entry.banned_until.is_some() {
+            return;
+        }
+        entry.failures.push(now);
+        if entry.failures.len() >= self.threshold {
+            warn!(
+                "Banning {} for {}s after {} auth failures within {}s",
+                ip,
+                self.duration.as_secs(),
+                entry.failures.len(),
+                self.window.as_secs()
+            );
+            entry.failures.clear();
+            entry.banned_until = Some(now + self.duration);
+        }
+    }
+
+    fn prune(&self, state: &mut HashMap<IpAddr, FailureState>, now: Instant) {
+        state.retain(|ip, s| {
+            if s.banned_until.is_some_and(|until| until <= now) {
+                info!("Ban expired for {}", ip);
+                s.banned_until = None;
+            }
+            s.failures.retain(|t| now.duration_since(*t) < self.window);
+            s.banned_until.is_some() || !s.failures.is_empty()
+        });
+    }
+}
+
+/// Limits how many connections are accepted per second.
+#[derive(Debug)]
+pub struct AcceptRateLimiter {
+    bucket: TokenBucket,
+    throttling: bool,
+}
+
+impl AcceptRateLimiter {
+    pub fn new(per_sec: u32) -> Self {
+        Self {
+            bucket: TokenBucket::new(per_sec.into()),
+            throttling: false,
+        }
+    }
+
+    /// Takes a token for a new connection, returning false if none is left.
+    pub fn try_accept(&mut self) -> bool {
+        if self.bucket.try_take(1) {
+            if self.throttling {
+                info!("Accept rate back under {}/s", self.bucket.rate());
+                self.throttling = false;
+            }
+            true
+        } else {
+            if !self.throttling {
+                warn!(
+                    "Accept rate exceeded {}/s, rejecting new connections",
+                    self.bucket.rate()
+                );
+                self.throttling = true;
+            }
+            false
+        }
+    }
+}
+
+/// Shell-style match where `*` is any run of characters and `?` any one.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and how much text it has swallowed so far
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn deny_takes_precedence_over_allow() {
+        let list = AccessList {
+            allow: vec![parse_net("192.0.2.0/24").unwrap()],
+            deny: vec![parse_net("192.0.2.7").unwrap()],
+        };
+        assert_eq!(list.check(ip("192.0.2.1")), None);
+        assert_eq!(
+            list.check(ip("192.0.2.7")),
+            Some("deny 192.0.2.7/32".to_string())
+        );
+        assert_eq!(
+            list.check(ip("198.51.100.1")),
+            Some("not in allow list".to_string())
+        );
+    }
+
+    #[test]
+    fn empty_list_permits_everyone() {
+        let list = AccessList::default();
+        assert!(list.is_empty());
+        assert_eq!(list.check(ip("192.0.2.1")), None);
+        assert_eq!(list.check(ip("2001:db8::1")), None);
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_rules() {
+        let list = AccessList {
+            allow: Vec::new(),
+            deny: vec![parse_net("192.0.2.0/24").unwrap()],
+        };
+        assert!(list.check(ip("::ffff:192.0.2.1")).is_some());
+    }
+
+    #[test]
+    fn parse_net_accepts_ranges_and_bare_addresses() {
+        assert_eq!(parse_net("10.0.0.0/8").unwrap().to_string(), "10.0.0.0/8");
+        assert_eq!(parse_net("10.1.2.3").unwrap().to_string(), "10.1.2.3/32");
+        assert_eq!(
+            parse_net("2001:db8::1").unwrap().to_string(),
+            "2001:db8::1/128"
+        );
+        assert!(parse_net("10.0.0.0/33").is_err());
+        assert!(parse_net("example.com").is_err());
+    }
+
+    #[test]
+    fn ban_triggers_after_threshold_failures() {
+        let bans = BanList::new(3, Duration::from_secs(60), Duration::from_secs(60));
+        bans.record_failure(ip("192.0.2.1"));
+        bans.record_failure(ip("192.0.2.1"));
+        assert!(!bans.is_banned(ip("192.0.2.1")));
+        bans.record_failure(ip("192.0.2.1"));
+        assert!(bans.is_banned(ip("192.0.2.1")));
+        assert!(!bans.is_banned(ip("192.0.2.2")));
+    }
+
+    #[test]
+    fn ban_expires() {
+        let bans = BanList::new(1, Duration::from_secs(60), Duration::from_millis(50));
+        bans.record_failure(ip("192.0.2.1"));
+        assert!(bans.is_banned(ip("192.0.2.1")));
+        std::thread::sleep(Duration::from_millis(80));
+        assert!(!bans.is_banned(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn failures_outside_the_window_are_forgotten() {
+        let bans = BanList::new(2, Duration::from_millis(50), Duration::from_secs(60));
+        bans.record_failure(ip("192.0.2.1"));
+        std::thread::sleep(Duration::from_millis(80));
+        bans.record_failure(ip("192.0.2.1"));
+        assert!(!bans.is_banned(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_share_a_ban() {
+        let bans = BanList::new(1, Duration::from_secs(60), Duration::from_secs(60));
+        bans.record_failure(ip("::ffff:192.0.2.1"));
+        assert!(bans.is_banned(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn accept_rate_refuses_past_the_burst() {
+        let mut limiter = AcceptRateLimiter::new(3);
+        assert!((0..3).all(|_| limiter.try_accept()));
+        assert!(!limiter.try_accept());
+    }
+
+    #[test]
+    fn glob_matches_stars_and_question_marks() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("dev-*", "dev-alice"));
+        assert!(glob_match("dev-?", "dev-a"));
+        assert!(!glob_match("dev-?", "dev-ab"));
+        assert!(glob_match("*-admin", "ops-admin"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(!glob_match("a*b*c", "axxbyy"));
+        assert!(!glob_match("alice", "alicex"));
+        assert!(!glob_match("", "a"));
+    }
+
+    #[test]
+    fn glob_backtracks_past_an_early_match() {
+        assert!(glob_match("*ab", "aab"));
+        assert!(glob_match("*a*b", "xaxxab"));
+        assert!(!glob_match("*ab", "aba"));
+    }
+}
@@ -0,0 +1,125 @@
+use anyhow::Result;
+use russh::keys::{Algorithm, EcdsaCurve, HashAlg};
+use russh::{Preferred, cipher, kex, mac};
+use std::borrow::Cow;
+
+// Always offered alongside a custom KEX list; dropping them would disable
+// ext-info and OpenSSH strict KEX (the Terrapin mitigation)
+const KEX_EXTENSIONS: &[kex::Name] = &[
+    kex::EXTENSION_SUPPORT_AS_CLIENT,
+    kex::EXTENSION_SUPPORT_AS_SERVER,
+    kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT,
+    kex::EXTENSION_OPENSSH_STRICT_KEX_AS_SERVER,
+];
+
+// Placeholder algorithms that would leave the connection unprotected
+const UNENCRYPTED: &[&str] = &["none", "clear"];
+
+/// Key exchange, cipher and MAC lists offered to clients, in order of
+/// preference. `None` keeps russh's defaults.
+#[derive(Debug, Clone, Default)]
+pub struct Algorithms {
+    pub kex: Option<Vec<kex::Name>>,
+    pub ciphers: Option<Vec<cipher::Name>>,
+    pub macs: Option<Vec<mac::Name>>,
+    /// Drop SHA-1 `ssh-rsa` host key signatures
+    pub no_sha1_host_keys: bool,
+}
+
+impl Algorithms {
+    /// Only modern AEAD ciphers, ETM MACs, curve25519/ML-KEM key exchange
+    /// and SHA-2 host key signatures.
+    pub fn modern() -> Self {
+        Self {
+            kex: Some(vec![
+                kex::MLKEM768X25519_SHA256,
+                kex::CURVE25519,
+                kex::CURVE25519_PRE_RFC_8731,
+            ]),
+            ciphers: Some(vec![
+                cipher::CHACHA20_POLY1305,
+                cipher::AES_256_GCM,
+                cipher::AES_128_GCM,
+            ]),
+            macs: Some(vec![mac::HMAC_SHA512_ETM, mac::HMAC_SHA256_ETM]),
+            no_sha1_host_keys: true,
+        }
+    }
+
+    pub fn parse_kex(name: &str) -> Result<kex::Name> {
+        parse_name(name, "key exchange", kex::ALL_KEX_ALGORITHMS)
+    }
+
+    pub fn parse_cipher(name: &str) -> Result<cipher::Name> {
+        parse_name(name, "cipher", cipher::ALL_CIPHERS)
+    }
+
+    pub fn parse_mac(name: &str) -> Result<mac::Name> {
+        parse_name(name, "MAC", mac::ALL_MAC_ALGORITHMS)
+    }
+
+    pub fn preferred(&self) -> Preferred {
+        let mut preferred = Preferred::default();
+        if let Some(kex) = &self.kex {
+            let mut kex = kex.clone();
+            for extension in KEX_EXTENSIONS {
+                if !kex.contains(extension) {
+                    kex.push(*extension);
+                }
+            }
+            preferred.kex = Cow::Owned(kex);
+        }
+        if let Some(ciphers) = &self.ciphers {
+            preferred.cipher = Cow::Owned(ciphers.clone());
+        }
+        if let Some(macs) = &self.macs {
+            preferred.mac = Cow::Owned(macs.clone());
+        }
+        if self.no_sha1_host_keys {
+            preferred.key = Cow::Owned(vec![
+                Algorithm::Ed25519,
+                Algorithm::Ecdsa {
+                    curve: EcdsaCurve::NistP256,
+                },
+                Algorithm::Ecdsa {
+                    curve: EcdsaCurve::NistP384,
+                },
+                Algorithm::Ecdsa {
+                    curve: EcdsaCurve::NistP521,
+                },
+                Algorithm::Rsa {
+                    hash: Some(HashAlg::Sha512),
+                },
+                Algorithm::Rsa {
+                    hash: Some(HashAlg::Sha256),
+                },
+            ]);
+        }
+        preferred
+    }
+}
+
+// Refuses unknown names and the unencrypted "none"/"clear", listing the
+// names russh does support
+fn parse_name<T: AsRef<str> + Copy>(name: &str, kind: &str, supported: &[&T]) -> Result<T> {
+    let name = name.trim();
+    anyhow::ensure!(
+        !UNENCRYPTED.contains(&name),
+        "The \"{}\" {} is not allowed",
+        name,
+        kind
+    );
+    let usable = supported
+        .iter()
+        .filter(|supported| !UNENCRYPTED.contains(&supported.as_ref()));
+    if let Some(found) = usable.clone().find(|supported| supported.as_ref() == name) {
+        return Ok(**found);
+    }
+    let names: Vec<_> = usable.map(|supported| supported.as_ref()).collect();
+    anyhow::bail!(
+        "Unsupported {}: {} (supported: {})",
+        kind,
+        name,
+        names.join(", ")
+    )
+}
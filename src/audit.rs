@@ -0,0 +1,120 @@
+use crate::handler::ByteCounts;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Append-only file with one JSON line per connection.
+#[derive(Debug)]
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    // The lock and a single write keep concurrent sessions' lines whole
+    fn append(&self, record: &AuditRecord) {
+        let mut line = match serde_json::to_string(record) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to encode audit record: {}", e);
+                return;
+            }
+        };
+        line.push('\n');
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("Failed to write audit log: {}", e);
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct AuditRecord {
+    session_id: u64,
+    client: Option<String>,
+    user: Option<String>,
+    auth_method: Option<String>,
+    /// Unix seconds
+    connected_at: u64,
+    disconnected_at: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    exit_reason: Option<String>,
+}
+
+/// One connection's audit record, shared by the handler and its reader
+/// task. Written to the log once the last holder drops it.
+#[derive(Debug)]
+pub(crate) struct SessionAudit {
+    log: Arc<AuditLog>,
+    bytes: Arc<ByteCounts>,
+    record: Mutex<AuditRecord>,
+}
+
+impl SessionAudit {
+    pub(crate) fn new(
+        log: Arc<AuditLog>,
+        session_id: u64,
+        client: Option<String>,
+        bytes: Arc<ByteCounts>,
+    ) -> Self {
+        Self {
+            log,
+            bytes,
+            record: Mutex::new(AuditRecord {
+                session_id,
+                client,
+                user: None,
+                auth_method: None,
+                connected_at: unix_now(),
+                disconnected_at: 0,
+                bytes_in: 0,
+                bytes_out: 0,
+                exit_reason: None,
+            }),
+        }
+    }
+
+    pub(crate) fn authenticated(&self, user: &str, method: &str) {
+        let mut record = self.record.lock().unwrap();
+        record.user = Some(user.to_string());
+        record.auth_method = Some(method.to_string());
+    }
+
+    /// Later reasons replace earlier ones, e.g. a respawned command's exit.
+    pub(crate) fn set_exit_reason(&self, reason: impl Into<String>) {
+        self.record.lock().unwrap().exit_reason = Some(reason.into());
+    }
+}
+
+impl Drop for SessionAudit {
+    fn drop(&mut self) {
+        let record = self.record.get_mut().unwrap();
+        record.disconnected_at = unix_now();
+        record.bytes_in = self.bytes.input.load(Ordering::Relaxed);
+        record.bytes_out = self.bytes.output.load(Ordering::Relaxed);
+        self.log.append(record);
+    }
+}
+
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
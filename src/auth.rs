@@ -0,0 +1,155 @@
+use anyhow::{Context, Result};
+use russh::keys::ssh_key::AuthorizedKeys;
+use russh::keys::{HashAlg, PublicKey};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+/// Resolves to whether an auth attempt succeeds.
+pub type AuthFuture<'a> = Pin<Box<dyn Future<Output = bool> + Send + 'a>>;
+
+/// Decides who gets in. Each method is called once per attempt; refusing
+/// `none` makes clients fall back to password or public key auth.
+///
+/// Methods default to refusing, so implementors only override what they
+/// support.
+pub trait Authenticator: Send + Sync {
+    fn authenticate_none<'a>(&'a self, _user: &'a str) -> AuthFuture<'a> {
+        Box::pin(async { false })
+    }
+
+    /// Also checks passwords typed at a keyboard-interactive prompt.
+    fn authenticate_password<'a>(&'a self, _user: &'a str, _password: &'a str) -> AuthFuture<'a> {
+        Box::pin(async { false })
+    }
+
+    fn authenticate_publickey<'a>(&'a self, _user: &'a str, _key: &'a PublicKey) -> AuthFuture<'a> {
+        Box::pin(async { false })
+    }
+}
+
+/// Lets everyone in with any method. The default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllowAllAuthenticator;
+
+impl Authenticator for AllowAllAuthenticator {
+    fn authenticate_none<'a>(&'a self, _user: &'a str) -> AuthFuture<'a> {
+        Box::pin(async { true })
+    }
+
+    fn authenticate_password<'a>(&'a self, _user: &'a str, _password: &'a str) -> AuthFuture<'a> {
+        Box::pin(async { true })
+    }
+
+    fn authenticate_publickey<'a>(&'a self, _user: &'a str, _key: &'a PublicKey) -> AuthFuture<'a> {
+        Box::pin(async { true })
+    }
+}
+
+/// Accepts public keys listed in an OpenSSH `authorized_keys` file, for
+/// any user name. Options in the file are ignored.
+#[derive(Debug, Clone)]
+pub struct FileAuthenticator {
+    keys: Vec<PublicKey>,
+}
+
+impl FileAuthenticator {
+    pub fn load(path: &Path) -> Result<Self> {
+        let keys = AuthorizedKeys::read_file(path)
+            .with_context(|| format!("Failed to read authorized keys from {}", path.display()))?
+            .into_iter()
+            .map(|entry| entry.public_key().clone())
+            .collect();
+        Ok(Self { keys })
+    }
+}
+
+impl Authenticator for FileAuthenticator {
+    fn authenticate_publickey<'a>(&'a self, _user: &'a str, key: &'a PublicKey) -> AuthFuture<'a> {
+        let found = self
+            .keys
+            .iter()
+            .any(|known| known.key_data() == key.key_data());
+        Box::pin(async move { found })
+    }
+}
+
+/// External program that decides whether an auth attempt succeeds.
+///
+/// Invoked as `<program> password <user>` with the password as a line on
+/// stdin, or `<program> publickey <user> <fingerprint>`. Exit status 0
+/// accepts.
+#[derive(Debug, Clone)]
+pub struct AuthCommand {
+    pub program: PathBuf,
+    pub timeout: Duration,
+}
+
+impl AuthCommand {
+    pub async fn check_password(&self, user: &str, password: &str) -> bool {
+        let line = format!("{}\n", password);
+        self.run(&["password", user], Some(line.as_bytes())).await
+    }
+
+    pub async fn check_publickey(&self, user: &str, fingerprint: &str) -> bool {
+        self.run(&["publickey", user, fingerprint], None).await
+    }
+
+    async fn run(&self, args: &[&str], stdin: Option<&[u8]>) -> bool {
+        let result = tokio::time::timeout(self.timeout, async {
+            let mut child = Command::new(&self.program)
+                .args(args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .kill_on_drop(true)
+                .spawn()?;
+
+            let mut pipe = child.stdin.take().expect("stdin is piped");
+            if let Some(data) = stdin {
+                pipe.write_all(data).await?;
+            }
+            drop(pipe);
+
+            child.wait().await
+        })
+        .await;
+
+        match result {
+            Ok(Ok(status)) => {
+                debug!("Auth command for {:?} exited with {}", args, status);
+                status.success()
+            }
+            Ok(Err(e)) => {
+                warn!("Auth command {} failed: {}", self.program.display(), e);
+                false
+            }
+            Err(_) => {
+                warn!(
+                    "Auth command {} timed out after {:?}",
+                    self.program.display(),
+                    self.timeout
+                );
+                false
+            }
+        }
+    }
+}
+
+impl Authenticator for AuthCommand {
+    fn authenticate_password<'a>(&'a self, user: &'a str, password: &'a str) -> AuthFuture<'a> {
+        Box::pin(self.check_password(user, password))
+    }
+
+    fn authenticate_publickey<'a>(&'a self, user: &'a str, key: &'a PublicKey) -> AuthFuture<'a> {
+        Box::pin(async move {
+            let fingerprint = key.fingerprint(HashAlg::Sha256).to_string();
+            self.check_publickey(user, &fingerprint).await
+        })
+    }
+}
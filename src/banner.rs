@@ -0,0 +1,42 @@
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+/// Text file shown to clients, re-read on SIGHUP.
+#[derive(Debug)]
+pub struct Banner {
+    path: PathBuf,
+    text: RwLock<String>,
+}
+
+impl Banner {
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let text = read_text(&path)?;
+        Ok(Self {
+            path,
+            text: RwLock::new(text),
+        })
+    }
+
+    pub fn reload(&self) -> Result<()> {
+        let text = read_text(&self.path)?;
+        *self.text.write().unwrap() = text;
+        Ok(())
+    }
+
+    pub fn text(&self) -> String {
+        self.text.read().unwrap().clone()
+    }
+}
+
+fn read_text(path: &PathBuf) -> Result<String> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(to_crlf(&text))
+}
+
+// SSH clients print the text as-is, so bare LFs would staircase
+fn to_crlf(text: &str) -> String {
+    text.replace("\r\n", "\n").replace('\n', "\r\n")
+}
@@ -0,0 +1,67 @@
+use russh::server::Handle;
+use russh::{ChannelId, CryptoVec};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+/// Pushes notices into every live terminal, e.g. before a restart.
+/// Cheap to clone; every clone sees the same sessions.
+#[derive(Debug, Clone, Default)]
+pub struct Broadcaster {
+    channels: Arc<Mutex<HashMap<(u64, ChannelId), Handle>>>,
+}
+
+impl Broadcaster {
+    pub(crate) fn register(&self, session_id: u64, channel: ChannelId, handle: Handle) {
+        self.channels
+            .lock()
+            .unwrap()
+            .insert((session_id, channel), handle);
+    }
+
+    pub(crate) fn unregister(&self, session_id: u64, channel: ChannelId) {
+        self.channels.lock().unwrap().remove(&(session_id, channel));
+    }
+
+    pub(crate) fn unregister_session(&self, session_id: u64) {
+        self.channels
+            .lock()
+            .unwrap()
+            .retain(|(id, _), _| *id != session_id);
+    }
+
+    /// Shows `message` in reverse video on the top line of every terminal,
+    /// leaving the cursor where the TUI had it. The line stays until the
+    /// TUI next redraws it. Control characters are dropped and only the
+    /// first line is used. Returns how many terminals it reached.
+    pub async fn send(&self, message: &str) -> usize {
+        let text: String = message
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .filter(|c| !c.is_control())
+            .collect();
+        // Save cursor, top-left, clear line, reverse video, reset, restore cursor
+        let notice = format!("\x1b7\x1b[1;1H\x1b[2K\x1b[7m[ {} ]\x1b[0m\x1b8", text);
+
+        let channels: Vec<_> = self
+            .channels
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&(_, channel), handle)| (channel, handle.clone()))
+            .collect();
+        let mut sent = 0;
+        for (channel, handle) in channels {
+            match handle
+                .data(channel, CryptoVec::from_slice(notice.as_bytes()))
+                .await
+            {
+                Ok(()) => sent += 1,
+                Err(_) => debug!("Skipping closed channel {:?} for broadcast", channel),
+            }
+        }
+        sent
+    }
+}
@@ -0,0 +1,142 @@
+use anyhow::{Context, Result, bail};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+// Period cpu.max quotas are expressed against, the kernel's default
+const CPU_PERIOD_USEC: u64 = 100_000;
+// How long a removed cgroup may take to empty after cgroup.kill
+const REMOVE_ATTEMPTS: u32 = 20;
+const REMOVE_INTERVAL: Duration = Duration::from_millis(50);
+
+static NEXT_CGROUP: AtomicU64 = AtomicU64::new(1);
+
+/// Kernel-enforced caps for each command run, applied through a cgroup v2
+/// created under `parent` (which the server must be able to write to).
+#[derive(Debug, Clone)]
+pub struct CgroupLimits {
+    pub parent: PathBuf,
+    /// Hard memory limit in bytes (memory.max)
+    pub memory_max: Option<u64>,
+    /// CPUs the command may use, e.g. 0.5 for half of one (cpu.max)
+    pub cpu_max: Option<f64>,
+}
+
+impl CgroupLimits {
+    /// Checks the parent is a cgroup v2 directory that has the controllers
+    /// the limits need, without changing anything.
+    pub fn check(&self) -> Result<()> {
+        let controllers = std::fs::read_to_string(self.parent.join("cgroup.controllers"))
+            .with_context(|| format!("{} is not a cgroup v2 directory", self.parent.display()))?;
+        for controller in self.controllers() {
+            if !controllers.split_whitespace().any(|c| c == controller) {
+                bail!(
+                    "The {} controller isn't available in {}",
+                    controller,
+                    self.parent.display()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the parent like [`check`](Self::check) and enables the
+    /// controllers the limits need for its children.
+    pub fn prepare(&self) -> Result<()> {
+        self.check()?;
+        for controller in self.controllers() {
+            write(
+                &self.parent,
+                "cgroup.subtree_control",
+                &format!("+{}", controller),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn controllers(&self) -> impl Iterator<Item = &'static str> {
+        [
+            self.memory_max.map(|_| "memory"),
+            self.cpu_max.map(|_| "cpu"),
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    /// Creates a cgroup for one command run and moves `pid` into it. The
+    /// child may fork before it's moved, so this is best kept to commands
+    /// that don't daemonize straight away.
+    pub(crate) fn attach(&self, pid: u32) -> Result<Cgroup> {
+        let name = format!(
+            "tuihost-{}-{}",
+            std::process::id(),
+            NEXT_CGROUP.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = self.parent.join(name);
+        std::fs::create_dir(&path)
+            .with_context(|| format!("Failed to create cgroup {}", path.display()))?;
+        // From here on dropping it removes the directory again
+        let cgroup = Cgroup { path };
+        if let Some(bytes) = self.memory_max {
+            write(&cgroup.path, "memory.max", &bytes.to_string())?;
+        }
+        if let Some(cpus) = self.cpu_max {
+            let quota = ((cpus * CPU_PERIOD_USEC as f64) as u64).max(1000);
+            write(
+                &cgroup.path,
+                "cpu.max",
+                &format!("{} {}", quota, CPU_PERIOD_USEC),
+            )?;
+        }
+        write(&cgroup.path, "cgroup.procs", &pid.to_string())?;
+        Ok(cgroup)
+    }
+}
+
+/// Parses a positive number of CPUs for `--cgroup-cpu-max`.
+pub fn parse_cpus(value: &str) -> Result<f64> {
+    match value.parse::<f64>() {
+        Ok(cpus) if cpus.is_finite() && cpus > 0.0 => Ok(cpus),
+        _ => bail!("expected a positive number of CPUs, e.g. 0.5 or 2"),
+    }
+}
+
+/// A command run's cgroup; anything left in it is killed and the directory
+/// removed on drop.
+#[derive(Debug)]
+pub(crate) struct Cgroup {
+    path: PathBuf,
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // cgroup.kill needs Linux 5.14; on older kernels stragglers keep the
+        // directory busy until they exit on their own
+        let _ = write(&self.path, "cgroup.kill", "1");
+        let path = std::mem::take(&mut self.path);
+        // Killing is asynchronous, so removal may need a few tries
+        std::thread::spawn(move || {
+            for _ in 0..REMOVE_ATTEMPTS {
+                match std::fs::remove_dir(&path) {
+                    Ok(()) => {
+                        debug!("Removed cgroup {}", path.display());
+                        return;
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+                    Err(_) => std::thread::sleep(REMOVE_INTERVAL),
+                }
+            }
+            warn!(
+                "Failed to remove cgroup {}, it's still in use",
+                path.display()
+            );
+        });
+    }
+}
+
+fn write(dir: &Path, file: &str, value: &str) -> Result<()> {
+    let path = dir.join(file);
+    std::fs::write(&path, value)
+        .with_context(|| format!("Failed to write {:?} to {}", value, path.display()))
+}
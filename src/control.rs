@@ -0,0 +1,205 @@
+use anyhow::{Context, Result, bail};
+use nix::sys::stat::{Mode, umask};
+use serde::Serialize;
+use std::fs::Permissions;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{debug, info, warn};
+use tuihost::{ActiveSession, Broadcaster, KillOutcome, SessionList};
+
+// Longest command line read, so a client can't make us buffer without end
+const MAX_LINE_LEN: u64 = 4096;
+
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// What the control socket can act on in the running server.
+pub struct Control {
+    pub sessions: SessionList,
+    pub broadcaster: Broadcaster,
+    /// Refuses new connections while set; also fails the readiness probe
+    pub draining: Arc<AtomicBool>,
+    /// Rereads whatever SIGHUP rereads
+    pub reload: Box<dyn Fn() + Send + Sync>,
+}
+
+/// The bound control socket; the file is removed again on drop.
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!(
+                "Failed to remove control socket {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Admin commands over a Unix socket only its owner can use, one per line:
+/// `list`, `kill <session_id>`, `broadcast <message>`, `drain on|off` and
+/// `reload`. Each gets one line back: JSON for `list`, `ok ...` or
+/// `error: ...` otherwise.
+pub fn serve(path: &Path, control: Control) -> Result<ControlSocket> {
+    // Left behind by a server that didn't exit cleanly, unless one still
+    // answers on it. Anything that isn't a socket is left alone.
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            bail!(
+                "Control socket path {} exists and is not a socket",
+                path.display()
+            );
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_err() {
+            std::fs::remove_file(path).with_context(|| {
+                format!("Failed to remove stale control socket {}", path.display())
+            })?;
+        }
+    }
+    // Created owner-only from the start; chmod after bind would leave a
+    // window where anyone the umask allows could connect
+    let previous = umask(Mode::from_bits_truncate(0o177));
+    let listener = UnixListener::bind(path);
+    umask(previous);
+    let listener =
+        listener.with_context(|| format!("Failed to bind control socket {}", path.display()))?;
+    let socket = ControlSocket {
+        path: path.to_path_buf(),
+    };
+    std::fs::set_permissions(path, Permissions::from_mode(0o600))
+        .with_context(|| format!("Failed to restrict control socket {}", path.display()))?;
+    info!("Control socket listening on {}", path.display());
+
+    let control = Arc::new(control);
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    // Usually fd exhaustion; back off instead of spinning
+                    warn!("Failed to accept control connection: {}", e);
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+            let control = control.clone();
+            tokio::spawn(async move {
+                if let Err(e) = respond(stream, &control).await {
+                    debug!("Control connection failed: {}", e);
+                }
+            });
+        }
+    });
+
+    Ok(socket)
+}
+
+async fn respond(stream: UnixStream, control: &Control) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    loop {
+        let mut line = String::new();
+        let n = (&mut reader)
+            .take(MAX_LINE_LEN)
+            .read_line(&mut line)
+            .await?;
+        if n == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') && n as u64 == MAX_LINE_LEN {
+            writer.write_all(b"error: line too long\n").await?;
+            return Ok(());
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut reply = execute(line, control).await;
+        reply.push('\n');
+        writer.write_all(reply.as_bytes()).await?;
+    }
+}
+
+async fn execute(line: &str, control: &Control) -> String {
+    let (command, arg) = line
+        .split_once(' ')
+        .map_or((line, ""), |(command, arg)| (command, arg.trim()));
+    match (command, arg) {
+        ("list", "") => {
+            let sessions: Vec<_> = control
+                .sessions
+                .snapshot()
+                .iter()
+                .map(SessionRecord::from)
+                .collect();
+            serde_json::to_string(&sessions)
+                .unwrap_or_else(|e| format!("error: failed to encode sessions: {}", e))
+        }
+        ("kill", id) => match id.parse::<u64>() {
+            Ok(id) => match control.sessions.kill(id).await {
+                KillOutcome::Killed => {
+                    info!("Session {} killed through the control socket", id);
+                    format!("ok killed {}", id)
+                }
+                KillOutcome::NotFound => format!("error: no session {}", id),
+                KillOutcome::AlreadyGone => format!("error: session {} already gone", id),
+            },
+            Err(_) => "error: usage: kill <session_id>".to_string(),
+        },
+        ("broadcast", "") => "error: usage: broadcast <message>".to_string(),
+        ("broadcast", message) => {
+            let sent = control.broadcaster.send(message).await;
+            info!("Broadcast sent to {} sessions", sent);
+            format!("ok sent to {} sessions", sent)
+        }
+        ("drain", "on" | "off") => {
+            let draining = arg == "on";
+            control.draining.store(draining, Ordering::SeqCst);
+            info!("Draining turned {} through the control socket", arg);
+            format!("ok draining {}", arg)
+        }
+        ("drain", _) => "error: usage: drain on|off".to_string(),
+        ("reload", "") => {
+            (control.reload)();
+            "ok reloaded".to_string()
+        }
+        _ => format!("error: unknown command: {}", line),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct SessionRecord {
+    id: u64,
+    client: Option<String>,
+    user: Option<String>,
+    /// Unix seconds
+    connected_at: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+impl From<&ActiveSession> for SessionRecord {
+    fn from(session: &ActiveSession) -> Self {
+        Self {
+            id: session.info.id,
+            client: session.info.addr.map(|addr| addr.to_string()),
+            user: session.info.user.clone(),
+            connected_at: session
+                .info
+                .connected_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            bytes_in: session.bytes_in,
+            bytes_out: session.bytes_out,
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use anyhow::{Context, Result};
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::{ForkResult, Pid, dup2, fork, setsid};
+use std::fs::OpenOptions;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Pid file removed again when dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Fails if `path` holds the pid of a running process. Stale files are
+    /// left for [`PidFile::create`] to overwrite.
+    pub fn check(path: &Path) -> Result<()> {
+        let Ok(contents) = std::fs::read_to_string(path) else {
+            return Ok(());
+        };
+        let Ok(pid) = contents.trim().parse::<i32>() else {
+            warn!("Ignoring unreadable pid file: {}", path.display());
+            return Ok(());
+        };
+        match kill(Pid::from_raw(pid), None) {
+            Err(Errno::ESRCH) => Ok(()),
+            _ => anyhow::bail!(
+                "Pid file {} belongs to running process {}",
+                path.display(),
+                pid
+            ),
+        }
+    }
+
+    pub fn create(path: &Path) -> Result<Self> {
+        Self::check(path)?;
+        std::fs::write(path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("Failed to write pid file {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Failed to remove pid file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Detaches from the terminal: double-forks, starts a new session and
+/// points stdin at /dev/null and stdout/stderr at `log_file` (or
+/// /dev/null). Must run before the tokio runtime starts. The working
+/// directory is kept so relative paths keep working.
+pub fn daemonize(log_file: Option<&Path>) -> Result<()> {
+    // Open before forking so errors still reach the terminal
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .context("Failed to open /dev/null")?;
+    let log = match log_file {
+        Some(path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open log file {}", path.display()))?,
+        None => null.try_clone()?,
+    };
+
+    // SAFETY: no other threads exist yet, the runtime hasn't started
+    if let ForkResult::Parent { .. } = unsafe { fork() }.context("Failed to fork")? {
+        std::process::exit(0);
+    }
+    setsid().context("Failed to start a new session")?;
+    // Second fork so the daemon can never reacquire a controlling terminal
+    if let ForkResult::Parent { .. } = unsafe { fork() }.context("Failed to fork")? {
+        std::process::exit(0);
+    }
+
+    dup2(null.as_raw_fd(), 0).context("Failed to redirect stdin")?;
+    dup2(log.as_raw_fd(), 1).context("Failed to redirect stdout")?;
+    dup2(log.as_raw_fd(), 2).context("Failed to redirect stderr")?;
+    Ok(())
+}
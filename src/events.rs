@@ -0,0 +1,43 @@
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+/// The connection an event is about.
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    /// Unique per connection for the life of the server
+    pub id: u64,
+    pub addr: Option<SocketAddr>,
+    /// Set once authentication succeeds
+    pub user: Option<String>,
+    /// When the connection was accepted
+    pub connected_at: SystemTime,
+}
+
+/// Callbacks for connection lifecycle events, e.g. to drive a dashboard or
+/// metrics. Every method defaults to doing nothing.
+///
+/// Methods are called inline on the connection's task, so they must not
+/// block; hand slow work off to a channel or a spawned task.
+pub trait EventObserver: Send + Sync {
+    /// A connection was accepted and holds a connection slot.
+    fn on_connect(&self, _session: &SessionInfo) {}
+
+    /// A connection was refused by the access list, ban list, accept rate
+    /// or connection limit. No further events follow for it.
+    fn on_reject(&self, _session: &SessionInfo, _reason: &str) {}
+
+    /// `method` is "none", "password", "publickey" or
+    /// "keyboard-interactive"; with a TOTP second factor this fires once the
+    /// code is accepted.
+    fn on_auth_success(&self, _session: &SessionInfo, _method: &str) {}
+
+    /// Anonymous probes refused before a real attempt aren't reported. A
+    /// wrong TOTP code is reported with method "keyboard-interactive".
+    fn on_auth_failure(&self, _session: &SessionInfo, _user: &str, _method: &str) {}
+
+    /// The user's command was spawned.
+    fn on_shell_start(&self, _session: &SessionInfo, _command: &str) {}
+
+    /// The connection is gone and its slot released.
+    fn on_disconnect(&self, _session: &SessionInfo) {}
+}
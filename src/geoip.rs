@@ -0,0 +1,74 @@
+use anyhow::{Context, Result, bail};
+use maxminddb::{MaxMindDBError, Reader, geoip2};
+use std::net::IpAddr;
+use std::path::Path;
+use tracing::warn;
+
+/// Admits or refuses connections by the country of their address, using a
+/// MaxMind GeoIP2/GeoLite2 Country or City database.
+#[derive(Debug)]
+pub struct CountryFilter {
+    reader: Reader<Vec<u8>>,
+    /// ISO 3166-1 alpha-2 codes; a non-empty list must contain the country
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    /// Whether addresses without a country (private ranges, gaps in the
+    /// database) get in
+    pub allow_unknown: bool,
+}
+
+impl CountryFilter {
+    /// Loads the whole database into memory. Allows everything until the
+    /// lists are filled in.
+    pub fn open(path: &Path) -> Result<Self> {
+        let reader = Reader::open_readfile(path)
+            .with_context(|| format!("Failed to load GeoIP database {}", path.display()))?;
+        Ok(Self {
+            reader,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            allow_unknown: true,
+        })
+    }
+
+    /// The country code for `ip`, if the database has one.
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        match self.reader.lookup::<geoip2::Country>(ip.to_canonical()) {
+            Ok(record) => record
+                .country
+                .and_then(|country| country.iso_code)
+                .map(str::to_string),
+            Err(MaxMindDBError::AddressNotFoundError(_)) => None,
+            Err(e) => {
+                warn!("GeoIP lookup failed for {}: {}", ip, e);
+                None
+            }
+        }
+    }
+
+    /// Returns why `ip` is refused, or `None` if it's permitted. Deny rules
+    /// take precedence, as with [`crate::AccessList`].
+    pub fn check(&self, ip: IpAddr) -> Option<String> {
+        let Some(country) = self.country(ip) else {
+            return (!self.allow_unknown).then(|| "unknown country".to_string());
+        };
+        if self.deny.contains(&country) {
+            return Some(format!("deny country {}", country));
+        }
+        if !self.allow.is_empty() && !self.allow.contains(&country) {
+            return Some(format!("country {} not in allow list", country));
+        }
+        None
+    }
+}
+
+/// Parses a two-letter country code, normalized to upper case.
+pub fn parse_country(s: &str) -> Result<String> {
+    if s.len() != 2 || !s.chars().all(|c| c.is_ascii_alphabetic()) {
+        bail!(
+            "invalid country code (expected two letters, e.g. DE): {}",
+            s
+        );
+    }
+    Ok(s.to_ascii_uppercase())
+}
@@ -1,60 +1,985 @@
-use crate::pty::{PtySession, PtyWriter};
-use crate::server::CmdConfig;
-use russh::server::{Auth, Handler, Msg, Session};
+use crate::access::{BanList, glob_match};
+use crate::audit::{SessionAudit, unix_now};
+use crate::auth::{AllowAllAuthenticator, Authenticator};
+use crate::broadcast::Broadcaster;
+use crate::events::{EventObserver, SessionInfo};
+use crate::pty::{PtyReader, PtySession, PtyUnavailable, PtyWriter, describe_exit};
+use crate::queue::ConnectionQueue;
+use crate::rate::TokenBucket;
+use crate::rdns;
+use crate::server::{CmdConfig, CommandMap, RespawnPolicy, SessionConfig};
+use crate::session_log::SessionLog;
+use crate::sessions::SessionList;
+use crate::shared::{SessionRegistry, SharedSession};
+use nix::sys::signal::Signal;
+use russh::keys::HashAlg;
+use russh::server::{Auth, Handle, Handler, Msg, Response, Session};
 use russh::{Channel, ChannelId, CryptoVec, Disconnect};
-use std::collections::HashMap;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Duration;
-use tokio::sync::Mutex;
-use tracing::{debug, error, info, warn};
-
-const MIN_PTY_COLS: u16 = 10;
-const MAX_PTY_COLS: u16 = 500;
-const MIN_PTY_ROWS: u16 = 5;
-const MAX_PTY_ROWS: u16 = 200;
+use russh::{MethodKind, MethodSet};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, Notify, OwnedMutexGuard, mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::{Instrument, Span, debug, error, info, info_span, trace, warn};
+
+const MAX_CLIENT_ENV_VARS: usize = 16;
+const MAX_CLIENT_ENV_VALUE_LEN: usize = 1024;
+// Channel opens refused over --max-channels-per-connection before the
+// client is disconnected
+const MAX_REFUSED_CHANNELS: usize = 10;
+// How long a child gets to exit after SIGHUP before it's killed
+const CHILD_KILL_GRACE: Duration = Duration::from_secs(2);
+// How long to wait for a command's exit status once its terminal closes
+const CHILD_EXIT_WAIT: Duration = Duration::from_secs(1);
+// OpenSSH discards channel output that arrives along with a failed shell
+// request, so the failure trails a refusal message by this much
+const REFUSED_SHELL_REPLY_DELAY: Duration = Duration::from_millis(100);
+// Input held per channel under --max-input-bps; a client that gets this far
+// ahead of the rate has its channel closed
+const INPUT_QUEUE_BYTES: usize = 256 * 1024;
+// Sent before a respawned command starts drawing
+const CLEAR_SCREEN: &[u8] = b"\x1b[H\x1b[2J";
+const ENTER_ALT_SCREEN: &[u8] = b"\x1b[?1049h";
+const LEAVE_ALT_SCREEN: &[u8] = b"\x1b[?1049l";
 
 pub struct SessionHandler {
-    tui_config: Arc<CmdConfig>,
-    pty_size: (u16, u16),
+    commands: Arc<CommandMap>,
+    session_config: Arc<SessionConfig>,
+    // Each channel's PTY request; without --multi-shell there is only one
+    terminals: HashMap<ChannelId, Terminal>,
+    pty_requested: bool,
+    // User and method of a first factor still waiting for its TOTP code
+    pending_totp: Option<(String, String)>,
+    // App picked with a `user+app` login name
+    app: Option<String>,
+    client_env: HashMap<ChannelId, Vec<(String, String)>>,
     pty_writers: Arc<Mutex<HashMap<ChannelId, Arc<Mutex<PtyWriter>>>>>,
+    // Dropping a channel's sender tells its reader task to terminate the command
+    pty_closers: HashMap<ChannelId, oneshot::Sender<()>>,
+    // Tags every event about a channel with its session and channel ids
+    channel_spans: HashMap<ChannelId, Span>,
+    open_channels: usize,
+    refused_channels: usize,
     client_addr: String,
+    session: SessionInfo,
     active_connections: Arc<AtomicUsize>,
+    // Told when this connection's slot is released
+    queue: Option<Arc<ConnectionQueue>>,
+    active_ptys: Arc<AtomicUsize>,
     shell_requested: bool,
+    shell_channels: HashSet<ChannelId>,
+    shell_started: bool,
+    // This connection's shell holds the server's single session
+    single_session_claimed: bool,
+    readonly: bool,
     max_session_duration: Option<Duration>,
+    ban_list: Option<Arc<BanList>>,
+    rejection: Option<String>,
+    authenticator: Arc<dyn Authenticator>,
+    observer: Option<Arc<dyn EventObserver>>,
+    audit: Option<Arc<SessionAudit>>,
+    session_log: Option<Arc<SessionLog>>,
+    // Why the command stopped, for the session end hook
+    end_reason: Arc<std::sync::Mutex<Option<String>>>,
+    bytes: Arc<ByteCounts>,
+    connected_at: Instant,
+    listen_addr: Option<SocketAddr>,
+    // Reverse DNS of the client, started at connect and awaited before the
+    // handshake or at the latest when a command is spawned
+    host_lookup: Option<JoinHandle<Option<String>>>,
+    client_host: Option<String>,
+    authenticated: Arc<Notify>,
+    kill: Arc<Notify>,
+    registry: Arc<SessionRegistry>,
+    // The shared session this connection drives or watches
+    shared: Option<Arc<SharedSession>>,
+    viewer: bool,
+    broadcaster: Broadcaster,
+    session_list: Option<SessionList>,
+    // Shared by all channels, each of which writes through its own queue
+    input_throttle: Option<Arc<std::sync::Mutex<TokenBucket>>>,
+    input_queues: HashMap<ChannelId, InputQueue>,
 }
 
 impl SessionHandler {
-    pub fn new(
-        tui_config: Arc<CmdConfig>,
-        client_addr: String,
+    pub(crate) fn new(
+        commands: Arc<CommandMap>,
+        session_config: Arc<SessionConfig>,
+        session: SessionInfo,
         active_connections: Arc<AtomicUsize>,
+        active_ptys: Arc<AtomicUsize>,
         max_session_duration: Option<Duration>,
+        ban_list: Option<Arc<BanList>>,
     ) -> Self {
+        let client_addr = session
+            .addr
+            .map(|a| a.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        let bytes = Arc::new(ByteCounts::default());
+        let input_throttle = (session_config.max_input_bps > 0).then(|| {
+            Arc::new(std::sync::Mutex::new(TokenBucket::new(
+                session_config.max_input_bps,
+            )))
+        });
+        let audit = session_config.audit_log.clone().map(|log| {
+            let client = session.addr.map(|addr| addr.to_string());
+            Arc::new(SessionAudit::new(log, session.id, client, bytes.clone()))
+        });
+        let host_lookup = session
+            .addr
+            .filter(|_| session_config.reverse_dns)
+            .map(|addr| tokio::spawn(rdns::lookup_host(addr.ip())));
+        let session_log = session_config.session_log_dir.as_ref().and_then(|dir| {
+            match SessionLog::create(dir, session.id) {
+                Ok(log) => {
+                    log.write(format_args!("connected from {}", client_addr));
+                    Some(Arc::new(log))
+                }
+                Err(e) => {
+                    warn!("{:#}", e);
+                    None
+                }
+            }
+        });
+
         Self {
-            tui_config,
-            pty_size: (80, 24),
+            commands,
+            session_config,
+            terminals: HashMap::new(),
+            pty_requested: false,
+            pending_totp: None,
+            app: None,
+            client_env: HashMap::new(),
             pty_writers: Arc::new(Mutex::new(HashMap::new())),
+            pty_closers: HashMap::new(),
+            channel_spans: HashMap::new(),
+            open_channels: 0,
+            refused_channels: 0,
             client_addr,
+            session,
             active_connections,
+            queue: None,
+            active_ptys,
             shell_requested: false,
+            shell_channels: HashSet::new(),
+            shell_started: false,
+            single_session_claimed: false,
+            readonly: false,
             max_session_duration,
+            ban_list,
+            rejection: None,
+            authenticator: Arc::new(AllowAllAuthenticator),
+            observer: None,
+            audit,
+            session_log,
+            end_reason: Arc::default(),
+            bytes,
+            connected_at: Instant::now(),
+            listen_addr: None,
+            host_lookup,
+            client_host: None,
+            authenticated: Arc::new(Notify::new()),
+            kill: Arc::new(Notify::new()),
+            registry: Arc::default(),
+            shared: None,
+            viewer: false,
+            broadcaster: Broadcaster::default(),
+            session_list: None,
+            input_throttle,
+            input_queues: HashMap::new(),
+        }
+    }
+
+    /// Marks the connection as refused: every auth attempt fails and no
+    /// further methods are offered. Rejected handlers don't hold a
+    /// connection slot.
+    pub(crate) fn reject(&mut self, reason: impl Into<String>) {
+        let reason = reason.into();
+        if let Some(audit) = &self.audit {
+            audit.set_exit_reason(format!("rejected: {}", reason));
+        }
+        self.rejection = Some(reason);
+    }
+
+    pub(crate) fn set_authenticator(&mut self, authenticator: Arc<dyn Authenticator>) {
+        self.authenticator = authenticator;
+    }
+
+    // Lists the connection until it's dropped
+    pub(crate) fn track(&mut self, session_list: SessionList) {
+        session_list.insert(self.session.clone(), self.bytes.clone(), self.kill.clone());
+        self.session_list = Some(session_list);
+    }
+
+    pub(crate) fn set_registry(&mut self, registry: Arc<SessionRegistry>) {
+        self.registry = registry;
+    }
+
+    pub(crate) fn set_queue(&mut self, queue: Option<Arc<ConnectionQueue>>) {
+        self.queue = queue;
+    }
+
+    pub(crate) fn set_broadcaster(&mut self, broadcaster: Broadcaster) {
+        self.broadcaster = broadcaster;
+    }
+
+    pub(crate) fn set_observer(&mut self, observer: Option<Arc<dyn EventObserver>>) {
+        self.observer = observer;
+    }
+
+    /// The local address the connection came in on, passed to the command
+    /// as `TUIHOST_LISTEN_ADDR`.
+    pub fn set_listen_addr(&mut self, addr: SocketAddr) {
+        self.listen_addr = Some(addr);
+    }
+
+    /// Notified once the client has authenticated, e.g. for accept loops
+    /// to enforce a deadline on the handshake.
+    pub fn authenticated(&self) -> Arc<Notify> {
+        self.authenticated.clone()
+    }
+
+    /// Notified when the connection is killed through
+    /// [`SessionList::kill`]; accept loops should disconnect it then.
+    pub fn killed(&self) -> Arc<Notify> {
+        self.kill.clone()
+    }
+
+    /// Bytes received from the client so far.
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes.input.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of command output sent to the client so far.
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes.output.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the client's reverse DNS lookup, if one is running, so log
+    /// lines from here on name its host. Returns false if the client has no
+    /// name and `require_reverse_dns` is set, in which case it should be
+    /// dropped.
+    pub async fn resolve_client_host(&mut self) -> bool {
+        if self.rejection.is_some() {
+            return true;
+        }
+        if let Some(lookup) = self.host_lookup.take() {
+            self.client_host = lookup.await.ok().flatten();
+            if let Some(host) = &self.client_host {
+                self.client_addr = format!("{} ({})", host, self.client_addr);
+            }
+        }
+        if self.client_host.is_some() || !self.session_config.require_reverse_dns {
+            return true;
+        }
+        warn!("Dropping {}: no reverse DNS", self.client_addr);
+        if let Some(audit) = &self.audit {
+            audit.set_exit_reason("rejected: no reverse DNS");
+        }
+        *self.end_reason.lock().unwrap() = Some("no reverse DNS".to_string());
+        false
+    }
+
+    /// Why the connection was refused, suitable for showing the client.
+    /// Accept loops can write it to the socket in place of the handshake.
+    pub fn rejection(&self) -> Option<&str> {
+        self.rejection.as_deref()
+    }
+
+    fn rejected_auth(&self, user: &str) -> Option<Auth> {
+        let reason = self.rejection.as_deref()?;
+        debug!(
+            "Rejecting auth for user: {} from {} ({})",
+            user, self.client_addr, reason
+        );
+        Some(Auth::Reject {
+            proceed_with_methods: Some(MethodSet::empty()),
+            partial_success: false,
+        })
+    }
+
+    // With apps configured, `alice+top` logs in as alice and runs the top
+    // app. The app of the latest attempt is kept, which is the one that
+    // succeeds since auth ends there.
+    fn split_app<'u>(&mut self, user: &'u str, method: &str) -> Result<&'u str, Auth> {
+        self.app = None;
+        let separator = self.session_config.app_separator.as_str();
+        if self.commands.apps.is_empty() || separator.is_empty() {
+            return Ok(user);
+        }
+        let Some((name, app)) = user.rsplit_once(separator) else {
+            return Ok(user);
+        };
+        if !self.commands.apps.contains_key(app) {
+            debug!(
+                "Rejecting unknown app {} for user: {} from {}",
+                app, name, self.client_addr
+            );
+            let auth = Auth::Reject {
+                proceed_with_methods: Some(MethodSet::empty()),
+                partial_success: false,
+            };
+            return Err(self.finish_auth(user, method, auth));
+        }
+        self.app = Some(app.to_string());
+        Ok(name)
+    }
+
+    // Usernames outside the allowed patterns fail without trying any
+    // authenticator, and with no methods left to try
+    fn disallowed_user(&mut self, user: &str, method: &str) -> Option<Auth> {
+        let patterns = &self.session_config.allowed_users;
+        if patterns.is_empty() || patterns.iter().any(|pattern| glob_match(pattern, user)) {
+            return None;
+        }
+        debug!(
+            "Rejecting disallowed user: {} from {}",
+            user, self.client_addr
+        );
+        let auth = Auth::Reject {
+            proceed_with_methods: Some(MethodSet::empty()),
+            partial_success: false,
+        };
+        Some(self.finish_auth(user, method, auth))
+    }
+
+    // Refuses a method that's switched off, telling the client which are on
+    fn disabled_method(&self, method: MethodKind) -> Option<Auth> {
+        let allowed = &self.session_config.auth_methods;
+        if allowed.contains(&method) {
+            return None;
+        }
+        debug!(
+            "Refusing disabled auth method {} from {}",
+            <&str>::from(&method),
+            self.client_addr
+        );
+        Some(Auth::Reject {
+            proceed_with_methods: Some(allowed.clone()),
+            partial_success: false,
+        })
+    }
+
+    // In honeypot mode, records the attempt and refuses it
+    fn honeypot_auth(
+        &self,
+        user: &str,
+        method: &str,
+        password: Option<&str>,
+        key: Option<&str>,
+    ) -> Option<Auth> {
+        let honeypot = self.session_config.honeypot.as_ref()?;
+        debug!(
+            "Honeypot {} attempt for user: {} from {}",
+            method, user, self.client_addr
+        );
+        honeypot.record(
+            self.session.id,
+            self.session.addr,
+            user,
+            method,
+            password,
+            key,
+        );
+        Some(Auth::reject())
+    }
+
+    // Holds back a successful first factor until a TOTP code is given,
+    // then records the result
+    fn finish_auth(&mut self, user: &str, method: &str, mut auth: Auth) -> Auth {
+        if auth == Auth::Accept
+            && self
+                .commands
+                .for_session(user, self.app.as_deref())
+                .is_none()
+        {
+            warn!(
+                "Rejecting user {} from {}: no command configured",
+                user, self.client_addr
+            );
+            auth = Auth::reject();
+        }
+
+        if auth == Auth::Accept
+            && let Some(totp) = &self.session_config.totp
+        {
+            if totp.has_secret(user) {
+                debug!(
+                    "Asking {} from {} for a verification code",
+                    user, self.client_addr
+                );
+                self.pending_totp = Some((user.to_string(), method.to_string()));
+                return Auth::Reject {
+                    proceed_with_methods: Some(MethodSet::from(
+                        &[MethodKind::KeyboardInteractive][..],
+                    )),
+                    partial_success: true,
+                };
+            }
+            if !totp.exempt_missing {
+                warn!(
+                    "Rejecting user {} from {}: no TOTP secret configured",
+                    user, self.client_addr
+                );
+                auth = Auth::reject();
+            }
+        }
+
+        self.record_auth(user, method, auth)
+    }
+
+    fn verify_totp(&mut self, user: &str, code: &str) -> Auth {
+        let (Some((_, method)), Some(totp)) =
+            (self.pending_totp.clone(), self.session_config.totp.clone())
+        else {
+            return Auth::reject();
+        };
+        if totp.verify(user, code, unix_now()) {
+            info!(
+                "Accepted verification code for user: {} from {}",
+                user, self.client_addr
+            );
+            self.pending_totp = None;
+            return self.record_auth(user, &method, Auth::Accept);
+        }
+        warn!(
+            "Rejecting verification code for user: {} from {}",
+            user, self.client_addr
+        );
+        self.record_auth(user, "keyboard-interactive", Auth::reject())
+    }
+
+    // Records the authenticated user and reports rejected attempts to the
+    // ban list and observer. Only final answers get here: a password or code,
+    // a signed key, or a user that can't log in at all. Probes with "none"
+    // or an offered key don't.
+    fn record_auth(&mut self, user: &str, method: &str, auth: Auth) -> Auth {
+        match auth {
+            Auth::Accept => {
+                self.session.user = Some(user.to_string());
+                self.readonly = self
+                    .session_config
+                    .readonly_users
+                    .iter()
+                    .any(|name| name == user);
+                if let Some(observer) = &self.observer {
+                    observer.on_auth_success(&self.session, method);
+                }
+                if let Some(audit) = &self.audit {
+                    audit.authenticated(user, method);
+                }
+                if let Some(session_list) = &self.session_list {
+                    session_list.set_user(self.session.id, user);
+                }
+                self.log_event(format_args!("authenticated as {} via {}", user, method));
+                self.authenticated.notify_one();
+            }
+            Auth::Reject { .. } => {
+                self.log_event(format_args!("{} auth failed for {}", method, user));
+                if let (Some(ban_list), Some(addr)) = (&self.ban_list, self.session.addr) {
+                    ban_list.record_failure(addr.ip());
+                }
+                if let Some(observer) = &self.observer {
+                    observer.on_auth_failure(&self.session, user, method);
+                }
+            }
+            _ => {}
+        }
+        auth
+    }
+
+    fn channel_span(&self, channel: ChannelId) -> Span {
+        self.channel_spans
+            .get(&channel)
+            .cloned()
+            .unwrap_or_else(|| info_span!("channel", session = self.session.id, channel = %channel))
+    }
+
+    fn log_event(&self, event: std::fmt::Arguments) {
+        if let Some(log) = &self.session_log {
+            log.write(event);
+        }
+    }
+
+    // Per-session copy of the user's command config with placeholders expanded
+    fn session_command(
+        &self,
+        channel: ChannelId,
+        client_host: Option<String>,
+    ) -> Option<CmdConfig> {
+        let name = self.session.user.as_deref()?;
+        let mut config = self
+            .commands
+            .for_session(name, self.app.as_deref())?
+            .clone();
+        for (key, value) in self.commands.env_for_user(name) {
+            config.env.retain(|(existing, _)| existing != key);
+            config.env.push((key.clone(), value.clone()));
+        }
+        let user = self
+            .session
+            .user
+            .as_deref()
+            .map(sanitize_user)
+            .unwrap_or_default();
+        let terminal = self.terminal(channel);
+        config.workdir = config.workdir.map(|dir| dir.replace("{user}", &user));
+        // Still a single argv entry, so a username can't add arguments
+        config.command = self.expand_placeholders(&config.command, &user, &terminal);
+        for arg in &mut config.args {
+            *arg = self.expand_placeholders(arg, &user, &terminal);
+        }
+        for (_, value) in &mut config.env {
+            *value = self.expand_placeholders(value, &user, &terminal);
+        }
+
+        // Ahead of the configured env, so explicit values there still win
+        let term = match &self.session_config.force_term {
+            Some(term) => term.clone(),
+            None => terminal.term,
+        };
+        let session_env = [
+            Some(("TUIHOST_SESSION_ID", self.session.id.to_string())),
+            self.listen_addr
+                .map(|addr| ("TUIHOST_LISTEN_ADDR", addr.to_string())),
+            client_host.map(|host| ("TUIHOST_CLIENT_HOST", host)),
+            (!term.is_empty()).then_some(("TERM", term)),
+        ];
+        let session_env = session_env
+            .into_iter()
+            .flatten()
+            .map(|(name, value)| (name.to_string(), value));
+        config.env.splice(0..0, session_env);
+
+        // Allowlisted client env overrides the configured env, unexpanded
+        for (name, value) in self.client_env.get(&channel).into_iter().flatten() {
+            config.env.retain(|(key, _)| key != name);
+            config.env.push((name.clone(), value.clone()));
         }
+        Some(config)
     }
 
-    fn clamp_pty_size(cols: u32, rows: u32) -> (u16, u16) {
-        let cols = (cols as u16).clamp(MIN_PTY_COLS, MAX_PTY_COLS);
-        let rows = (rows as u16).clamp(MIN_PTY_ROWS, MAX_PTY_ROWS);
+    // Unknown placeholders are left as they are
+    fn expand_placeholders(&self, text: &str, user: &str, terminal: &Terminal) -> String {
+        if !text.contains('{') {
+            return text.to_string();
+        }
+        let (cols, rows) = terminal.size;
+        let (client_ip, client_port) = self
+            .session
+            .addr
+            .map(|addr| (addr.ip().to_string(), addr.port().to_string()))
+            .unwrap_or_default();
+        text.replace("{user}", user)
+            .replace("{client_ip}", &client_ip)
+            .replace("{client_port}", &client_port)
+            .replace("{session_id}", &self.session.id.to_string())
+            .replace("{cols}", &cols.to_string())
+            .replace("{rows}", &rows.to_string())
+            .replace("{term}", &terminal.term)
+    }
+
+    // A channel without a PTY request still gets a default-sized terminal
+    fn terminal(&self, channel: ChannelId) -> Terminal {
+        self.terminals.get(&channel).cloned().unwrap_or_default()
+    }
+
+    // Fails a shell request and closes its channel, first telling the user
+    // why if there's a message
+    fn refuse_shell(
+        &self,
+        channel: ChannelId,
+        session: &mut Session,
+        message: &str,
+    ) -> Result<(), russh::Error> {
+        if message.is_empty() {
+            session.channel_failure(channel)?;
+            session.close(channel)?;
+            return Ok(());
+        }
+        let line = format!("{}\r\n", message);
+        session.data(channel, CryptoVec::from_slice(line.as_bytes()))?;
+        let handle = session.handle();
+        tokio::spawn(async move {
+            tokio::time::sleep(REFUSED_SHELL_REPLY_DELAY).await;
+            let _ = handle.channel_failure(channel).await;
+            let _ = handle.close(channel).await;
+        });
+        Ok(())
+    }
+
+    fn clamp_pty_size(&self, cols: u32, rows: u32) -> (u16, u16) {
+        let cfg = &self.session_config;
+        let cols = cols.clamp(cfg.min_cols as u32, cfg.max_cols as u32) as u16;
+        let rows = rows.clamp(cfg.min_rows as u32, cfg.max_rows as u32) as u16;
         (cols, rows)
     }
+
+    fn is_below_min_size(&self, cols: u32, rows: u32) -> bool {
+        cols < self.session_config.min_cols as u32 || rows < self.session_config.min_rows as u32
+    }
+}
+
+// Why a session's reader task stopped
+enum SessionEnd {
+    // The command exited (and wasn't respawned)
+    Exited,
+    // The channel or connection went away while the command was running
+    ClientGone,
+    // --max-output-bytes were forwarded
+    OutputLimit,
+    // --max-session-duration ran out
+    Expired(Duration),
+}
+
+// What a channel's PTY request asked for
+#[derive(Debug, Clone)]
+struct Terminal {
+    size: (u16, u16),
+    modes: Vec<(russh::Pty, u32)>,
+    term: String,
+}
+
+impl Default for Terminal {
+    fn default() -> Self {
+        Self {
+            size: (80, 24),
+            modes: Vec::new(),
+            term: String::new(),
+        }
+    }
+}
+
+/// Re-runs a session's command when it exits, refusing once it has exited
+/// `max` times within `window`.
+struct Respawner {
+    policy: RespawnPolicy,
+    command: CmdConfig,
+    modes: Vec<(russh::Pty, u32)>,
+    history: VecDeque<Instant>,
+}
+
+impl Respawner {
+    fn new(policy: RespawnPolicy, command: CmdConfig, modes: Vec<(russh::Pty, u32)>) -> Self {
+        Self {
+            policy,
+            command,
+            modes,
+            history: VecDeque::new(),
+        }
+    }
+
+    /// Spawns the command again at the current size and swaps the new input
+    /// half into `writer`, returning the output half.
+    async fn respawn(&mut self, writer: &Mutex<PtyWriter>) -> anyhow::Result<PtyReader> {
+        let now = Instant::now();
+        while self
+            .history
+            .front()
+            .is_some_and(|&at| now.duration_since(at) > self.policy.window)
+        {
+            self.history.pop_front();
+        }
+        anyhow::ensure!(
+            self.history.len() < self.policy.max,
+            "command exited {} times within {}s",
+            self.history.len() + 1,
+            self.policy.window.as_secs()
+        );
+        self.history.push_back(now);
+
+        spawn_into(writer, &self.command, &self.modes).await
+    }
+}
+
+// Starts `command` at the current size in place of the one that exited,
+// swapping the new input half into `writer` and returning the output half
+async fn spawn_into(
+    writer: &Mutex<PtyWriter>,
+    command: &CmdConfig,
+    modes: &[(russh::Pty, u32)],
+) -> anyhow::Result<PtyReader> {
+    let mut writer = writer.lock().await;
+    let (cols, rows) = writer.size();
+    let (reader, new_writer) = PtySession::spawn(command, modes, cols, rows)?.split();
+    *writer = new_writer;
+    Ok(reader)
+}
+
+// A command that stops reading stdin mustn't park this connection, so
+// writes give up after `timeout`
+async fn write_input(writer: &Mutex<PtyWriter>, data: &[u8], timeout: Duration, client_addr: &str) {
+    match writer.lock().await.write_within(data, timeout).await {
+        Ok(written) if written < data.len() => debug!(
+            "Command for {} isn't reading input, dropped {} bytes",
+            client_addr,
+            data.len() - written
+        ),
+        Ok(_) => {}
+        Err(e) => warn!("Failed to write to PTY for {}: {}", client_addr, e),
+    }
+}
+
+// A channel's input waiting for the --max-input-bps rate, capped at
+// INPUT_QUEUE_BYTES
+struct InputQueue {
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl InputQueue {
+    // Queues `data` without waiting; false if it would go over the cap
+    fn try_send(&self, data: &[u8]) -> bool {
+        if self.queued.load(Ordering::Relaxed) + data.len() > INPUT_QUEUE_BYTES {
+            return false;
+        }
+        self.queued.fetch_add(data.len(), Ordering::Relaxed);
+        self.tx.send(data.to_vec()).is_ok()
+    }
+}
+
+// Writes a channel's input at the --max-input-bps rate in its own task, so
+// the handler only ever queues and never waits on the rate or the command.
+// Looks the writer up per packet, as a respawned command gets a new one.
+fn spawn_input_task(
+    pty_writers: Arc<Mutex<HashMap<ChannelId, Arc<Mutex<PtyWriter>>>>>,
+    channel: ChannelId,
+    throttle: Arc<std::sync::Mutex<TokenBucket>>,
+    timeout: Duration,
+    client_addr: String,
+) -> InputQueue {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    let queued = Arc::new(AtomicUsize::new(0));
+    let task_queued = queued.clone();
+    tokio::spawn(
+        async move {
+            while let Some(data) = rx.recv().await {
+                let wait = throttle.lock().unwrap().take(data.len() as u64);
+                tokio::time::sleep(wait).await;
+                let writer = pty_writers.lock().await.get(&channel).cloned();
+                if let Some(writer) = writer {
+                    write_input(&writer, &data, timeout, &client_addr).await;
+                }
+                task_queued.fetch_sub(data.len(), Ordering::Relaxed);
+            }
+        }
+        .in_current_span(),
+    );
+    InputQueue { tx, queued }
+}
+
+// Gathers command output into fewer, larger packets: reads are held until
+// `max_bytes` pile up or `interval` has passed since the oldest one. A zero
+// interval sends every read straight away.
+struct OutputBuffer {
+    pending: Vec<u8>,
+    interval: Duration,
+    max_bytes: usize,
+    // When held output has to go out; None while nothing is held
+    deadline: Option<tokio::time::Instant>,
+}
+
+impl OutputBuffer {
+    fn new(interval: Duration, max_bytes: usize) -> Self {
+        Self {
+            pending: Vec::new(),
+            interval,
+            max_bytes,
+            deadline: None,
+        }
+    }
+
+    // Holds `data`; true once it's time to flush
+    fn push(&mut self, data: &[u8]) -> bool {
+        self.pending.extend_from_slice(data);
+        self.deadline
+            .get_or_insert_with(|| tokio::time::Instant::now() + self.interval);
+        self.interval.is_zero() || self.pending.len() >= self.max_bytes
+    }
+
+    // Sends whatever is held; false if the client is gone
+    async fn flush(&mut self, handle: &Handle, channel: ChannelId) -> bool {
+        self.deadline = None;
+        if self.pending.is_empty() {
+            return true;
+        }
+        let data = CryptoVec::from_slice(&self.pending);
+        self.pending.clear();
+        handle.data(channel, data).await.is_ok()
+    }
+}
+
+// Traffic of one connection; relaxed atomics keep the interactive path cheap
+#[derive(Debug, Default)]
+pub(crate) struct ByteCounts {
+    pub(crate) input: AtomicU64,
+    pub(crate) output: AtomicU64,
+}
+
+// A reserved slot in the global PTY count, released on drop
+struct PtySlot(Arc<AtomicUsize>);
+
+impl PtySlot {
+    fn acquire(active_ptys: &Arc<AtomicUsize>, max_ptys: usize) -> Option<Self> {
+        let current = active_ptys.fetch_add(1, Ordering::SeqCst);
+        let slot = Self(active_ptys.clone());
+        (max_ptys == 0 || current < max_ptys).then_some(slot)
+    }
+}
+
+impl Drop for PtySlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Periodically nudges the child to redraw. Stops once the channel closes and
+// its writer is dropped.
+fn spawn_refresh_task(writer: Weak<Mutex<PtyWriter>>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let Some(writer) = writer.upgrade() else {
+                break;
+            };
+            if let Err(e) = writer.lock().await.refresh() {
+                debug!("Stopping PTY refresh: {}", e);
+                break;
+            }
+        }
+    });
+}
+
+// Leaves the alternate screen entered for --alt-screen, so the client's
+// scrollback and any closing message stay visible
+async fn restore_screen(handle: &Handle, channel: ChannelId, alt_screen: bool) {
+    if alt_screen {
+        let _ = handle
+            .data(channel, CryptoVec::from_slice(LEAVE_ALT_SCREEN))
+            .await;
+    }
+}
+
+// Types `input` into the command once it has had `delay` to set up its
+// terminal. Holding the writer until then queues client keystrokes behind it.
+fn spawn_initial_input(mut writer: OwnedMutexGuard<PtyWriter>, input: Vec<u8>, delay: Duration) {
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        if let Err(e) = writer.write_all(&input).await {
+            debug!("Failed to write initial input: {}", e);
+        }
+    });
+}
+
+// Resizes arrive as window changes, so a client-sent SIGWINCH is refused
+fn unix_signal(signal: &russh::Sig) -> Option<Signal> {
+    let signal = match signal {
+        russh::Sig::ABRT => Signal::SIGABRT,
+        russh::Sig::ALRM => Signal::SIGALRM,
+        russh::Sig::FPE => Signal::SIGFPE,
+        russh::Sig::HUP => Signal::SIGHUP,
+        russh::Sig::ILL => Signal::SIGILL,
+        russh::Sig::INT => Signal::SIGINT,
+        russh::Sig::KILL => Signal::SIGKILL,
+        russh::Sig::PIPE => Signal::SIGPIPE,
+        russh::Sig::QUIT => Signal::SIGQUIT,
+        russh::Sig::SEGV => Signal::SIGSEGV,
+        russh::Sig::TERM => Signal::SIGTERM,
+        russh::Sig::USR1 => Signal::SIGUSR1,
+        russh::Sig::Custom(name) => format!("SIG{}", name).parse().ok()?,
+    };
+    (signal != Signal::SIGWINCH).then_some(signal)
+}
+
+// `hexdump -C` style dump of up to `limit` bytes, for --debug-io
+fn hex_dump(data: &[u8], limit: usize) -> String {
+    let mut dump = String::new();
+    for (row, chunk) in data[..data.len().min(limit)].chunks(16).enumerate() {
+        let hex: String = chunk.iter().map(|byte| format!("{:02x} ", byte)).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&byte| match byte {
+                b' '..=b'~' => byte as char,
+                _ => '.',
+            })
+            .collect();
+        let _ = write!(dump, "\n{:08x}  {:<48} |{}|", row * 16, hex, ascii);
+    }
+    if data.len() > limit {
+        let _ = write!(dump, "\n... {} more bytes", data.len() - limit);
+    }
+    dump
+}
+
+// Usernames are client-controlled, so keep only characters safe to embed in
+// paths and arguments (no leading dot or dash)
+fn sanitize_user(user: &str) -> String {
+    user.chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        .take(32)
+        .collect::<String>()
+        .trim_start_matches(['.', '-'])
+        .to_string()
 }
 
 impl Drop for SessionHandler {
     fn drop(&mut self) {
+        if self.rejection.is_some() {
+            return;
+        }
+        self.broadcaster.unregister_session(self.session.id);
+        if let Some(session_list) = &self.session_list {
+            session_list.remove(self.session.id);
+        }
+        if self.viewer
+            && let Some(shared) = &self.shared
+        {
+            shared.detach(self.session.id);
+        }
+        // Only once the connection is gone, so everything the command
+        // printed has reached the client
+        if self.single_session_claimed
+            && let Some(single) = &self.session_config.single_session
+        {
+            single.end();
+        }
+        if self.shell_started
+            && let Some(hooks) = &self.session_config.hooks
+        {
+            // The command's task may not have noticed the disconnect yet
+            let reason = self.end_reason.lock().unwrap().clone();
+            hooks.session_ended(
+                &self.session,
+                reason.as_deref().unwrap_or("client disconnected"),
+            );
+        }
         let prev = self.active_connections.fetch_sub(1, Ordering::SeqCst);
-        debug!(
-            "Connection closed from {} ({} remaining)",
+        if let Some(queue) = &self.queue {
+            queue.slot_freed();
+        }
+        if let Some(observer) = &self.observer {
+            observer.on_disconnect(&self.session);
+        }
+        self.log_event(format_args!(
+            "closed after {}s: {} bytes in, {} bytes out",
+            self.connected_at.elapsed().as_secs(),
+            self.bytes_in(),
+            self.bytes_out()
+        ));
+        info!(
+            "Session {} from {} closed after {}s: {} bytes in, {} bytes out ({} remaining)",
+            self.session.id,
             self.client_addr,
+            self.connected_at.elapsed().as_secs(),
+            self.bytes_in(),
+            self.bytes_out(),
             prev - 1
         );
     }
@@ -64,65 +989,342 @@ impl Handler for SessionHandler {
     type Error = russh::Error;
 
     async fn auth_none(&mut self, user: &str) -> Result<Auth, Self::Error> {
+        if let Some(auth) = self.rejected_auth(user) {
+            return Ok(auth);
+        }
+        let user = match self.split_app(user, "none") {
+            Ok(user) => user,
+            Err(auth) => return Ok(auth),
+        };
+        // Makes clients go on to send credentials worth recording
+        if self.session_config.honeypot.is_some() {
+            return Ok(Auth::reject());
+        }
+        if let Some(auth) = self.disabled_method(MethodKind::None) {
+            return Ok(auth);
+        }
+        if let Some(auth) = self.disallowed_user(user, "none") {
+            return Ok(auth);
+        }
+        let authenticator = self.authenticator.clone();
+        if !authenticator.authenticate_none(user).await {
+            // Clients probe with "none" first, so this isn't counted as a failure
+            debug!(
+                "Rejecting anonymous auth for user: {} from {}",
+                user, self.client_addr
+            );
+            return Ok(Auth::reject());
+        }
         info!(
             "Accepting anonymous auth for user: {} from {}",
             user, self.client_addr
         );
+        Ok(self.finish_auth(user, "none", Auth::Accept))
+    }
+
+    async fn auth_password(&mut self, user: &str, password: &str) -> Result<Auth, Self::Error> {
+        if let Some(auth) = self.rejected_auth(user) {
+            return Ok(auth);
+        }
+        let user = match self.split_app(user, "password") {
+            Ok(user) => user,
+            Err(auth) => return Ok(auth),
+        };
+        if let Some(auth) = self.honeypot_auth(user, "password", Some(password), None) {
+            return Ok(auth);
+        }
+        if let Some(auth) = self.disabled_method(MethodKind::Password) {
+            return Ok(auth);
+        }
+        if let Some(auth) = self.disallowed_user(user, "password") {
+            return Ok(auth);
+        }
+        let authenticator = self.authenticator.clone();
+        if !authenticator.authenticate_password(user, password).await {
+            info!(
+                "Rejecting password auth for user: {} from {}",
+                user, self.client_addr
+            );
+            return Ok(self.finish_auth(user, "password", Auth::reject()));
+        }
+        info!(
+            "Accepting password auth for user: {} from {}",
+            user, self.client_addr
+        );
+        Ok(self.finish_auth(user, "password", Auth::Accept))
+    }
+
+    // Keys are offered before the client proves it holds them, and clients
+    // offer every key they have, so refusing one here is a probe rather
+    // than a failed login
+    async fn auth_publickey_offered(
+        &mut self,
+        user: &str,
+        public_key: &russh::keys::PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        if let Some(auth) = self.rejected_auth(user) {
+            return Ok(auth);
+        }
+        let user = match self.split_app(user, "publickey") {
+            Ok(user) => user,
+            Err(auth) => return Ok(auth),
+        };
+        let fingerprint = public_key.fingerprint(HashAlg::Sha256).to_string();
+        if let Some(auth) = self.honeypot_auth(user, "publickey", None, Some(&fingerprint)) {
+            return Ok(auth);
+        }
+        if let Some(auth) = self.disabled_method(MethodKind::PublicKey) {
+            return Ok(auth);
+        }
+        if let Some(auth) = self.disallowed_user(user, "publickey") {
+            return Ok(auth);
+        }
+        let authenticator = self.authenticator.clone();
+        if !authenticator.authenticate_publickey(user, public_key).await {
+            debug!(
+                "Rejecting offered publickey {} for user: {} from {}",
+                fingerprint, user, self.client_addr
+            );
+            return Ok(Auth::reject());
+        }
         Ok(Auth::Accept)
     }
 
-    async fn auth_password(&mut self, user: &str, _password: &str) -> Result<Auth, Self::Error> {
+    // Only reached with a valid signature. Checked again, as the signed key
+    // needn't be the one that was offered.
+    async fn auth_publickey(
+        &mut self,
+        user: &str,
+        public_key: &russh::keys::PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        if let Some(auth) = self.rejected_auth(user) {
+            return Ok(auth);
+        }
+        let user = match self.split_app(user, "publickey") {
+            Ok(user) => user,
+            Err(auth) => return Ok(auth),
+        };
+        let fingerprint = public_key.fingerprint(HashAlg::Sha256).to_string();
+        if let Some(auth) = self.disabled_method(MethodKind::PublicKey) {
+            return Ok(auth);
+        }
+        if let Some(auth) = self.disallowed_user(user, "publickey") {
+            return Ok(auth);
+        }
+        let authenticator = self.authenticator.clone();
+        if !authenticator.authenticate_publickey(user, public_key).await {
+            info!(
+                "Rejecting publickey {} for user: {} from {}",
+                fingerprint, user, self.client_addr
+            );
+            return Ok(self.finish_auth(user, "publickey", Auth::reject()));
+        }
+        info!(
+            "Accepting publickey auth for user: {} from {}",
+            user, self.client_addr
+        );
+        Ok(self.finish_auth(user, "publickey", Auth::Accept))
+    }
+
+    // Asks for the TOTP code after a first factor, and in honeypot mode
+    // collects what clients type at a password prompt
+    async fn auth_keyboard_interactive<'a>(
+        &'a mut self,
+        user: &str,
+        _submethods: &str,
+        response: Option<Response<'a>>,
+    ) -> Result<Auth, Self::Error> {
+        if let Some(auth) = self.rejected_auth(user) {
+            return Ok(auth);
+        }
+        let user = match self.split_app(user, "keyboard-interactive") {
+            Ok(user) => user,
+            Err(auth) => return Ok(auth),
+        };
+        let awaiting_code = self
+            .pending_totp
+            .as_ref()
+            .is_some_and(|(pending, _)| pending == user);
+        let honeypot = self.session_config.honeypot.is_some();
+        if !awaiting_code && !honeypot {
+            if let Some(auth) = self.disabled_method(MethodKind::KeyboardInteractive) {
+                return Ok(auth);
+            }
+            if let Some(auth) = self.disallowed_user(user, "keyboard-interactive") {
+                return Ok(auth);
+            }
+        }
+        let Some(mut response) = response else {
+            let prompt = if awaiting_code {
+                "Verification code: "
+            } else {
+                "Password: "
+            };
+            return Ok(Auth::Partial {
+                name: Cow::Borrowed(""),
+                instructions: Cow::Borrowed(""),
+                prompts: Cow::Owned(vec![(Cow::Borrowed(prompt), false)]),
+            });
+        };
+        let answer = response
+            .next()
+            .map(|answer| String::from_utf8_lossy(&answer).into_owned())
+            .unwrap_or_default();
+        if awaiting_code {
+            return Ok(self.verify_totp(user, &answer));
+        }
+        if let Some(auth) = self.honeypot_auth(user, "keyboard-interactive", Some(&answer), None) {
+            return Ok(auth);
+        }
+        // A password prompt, checked like password auth
+        let authenticator = self.authenticator.clone();
+        if !authenticator.authenticate_password(user, &answer).await {
+            info!(
+                "Rejecting keyboard-interactive auth for user: {} from {}",
+                user, self.client_addr
+            );
+            return Ok(self.finish_auth(user, "keyboard-interactive", Auth::reject()));
+        }
         info!(
-            "Accepting password auth for user: {} from {}",
+            "Accepting keyboard-interactive auth for user: {} from {}",
             user, self.client_addr
         );
-        Ok(Auth::Accept)
+        Ok(self.finish_auth(user, "keyboard-interactive", Auth::Accept))
     }
 
-    async fn auth_publickey(
-        &mut self,
-        user: &str,
-        _public_key: &russh::keys::PublicKey,
-    ) -> Result<Auth, Self::Error> {
-        info!(
-            "Accepting publickey auth for user: {} from {}",
-            user, self.client_addr
-        );
-        Ok(Auth::Accept)
+    async fn authentication_banner(&mut self) -> Result<Option<String>, Self::Error> {
+        if let Some(reason) = &self.rejection {
+            return Ok(Some(format!("{}\r\n", reason)));
+        }
+        Ok(self
+            .session_config
+            .banner
+            .as_ref()
+            .map(|banner| banner.text()))
     }
 
     async fn channel_open_session(
         &mut self,
         channel: Channel<Msg>,
-        _session: &mut Session,
+        session: &mut Session,
     ) -> Result<bool, Self::Error> {
+        // Nothing here awaits, so the span can simply be entered
+        let span = self.channel_span(channel.id());
+        let _entered = span.enter();
+        if let Some(session_list) = &self.session_list {
+            session_list.set_handle(self.session.id, session.handle());
+        }
         debug!(
             "Channel open session: {:?} from {}",
             channel.id(),
             self.client_addr
         );
+        let max_channels = self.session_config.max_channels;
+        if max_channels > 0 && self.open_channels >= max_channels {
+            warn!(
+                "Refusing session channel from {}: {} channels already open (max {})",
+                self.client_addr, self.open_channels, max_channels
+            );
+            self.refused_channels += 1;
+            if self.refused_channels >= MAX_REFUSED_CHANNELS {
+                error!(
+                    "SECURITY: {} refused channel opens from {} - disconnecting",
+                    self.refused_channels, self.client_addr
+                );
+                session.disconnect(Disconnect::ByApplication, "too many channels", "en")?;
+            }
+            return Ok(false);
+        }
+        self.open_channels += 1;
+        self.channel_spans.insert(channel.id(), span.clone());
         Ok(true)
     }
 
     async fn pty_request(
         &mut self,
         channel: ChannelId,
-        _term: &str,
+        term: &str,
         col_width: u32,
         row_height: u32,
         _pix_width: u32,
         _pix_height: u32,
-        _modes: &[(russh::Pty, u32)],
+        modes: &[(russh::Pty, u32)],
         session: &mut Session,
     ) -> Result<(), Self::Error> {
-        let (cols, rows) = Self::clamp_pty_size(col_width, row_height);
-        debug!(
-            "PTY request for channel {:?}: {}x{} (requested {}x{}) from {}",
-            channel, cols, rows, col_width, row_height, self.client_addr
-        );
-        self.pty_size = (cols, rows);
-        session.channel_success(channel)?;
-        Ok(())
+        let span = self.channel_span(channel);
+        async move {
+            // Resizes go through window_change_request; like the shell, a
+            // channel gets one terminal, and so does a connection unless
+            // --multi-shell allows several
+            if self.terminals.contains_key(&channel)
+                || (self.pty_requested && !self.session_config.multi_shell)
+            {
+                error!(
+                    "SECURITY: duplicate PTY request for channel {:?} from {} - disconnecting",
+                    channel, self.client_addr
+                );
+                session.disconnect(Disconnect::ByApplication, "duplicate PTY request", "en")?;
+                return Ok(());
+            }
+            if self.session_config.reject_small && self.is_below_min_size(col_width, row_height) {
+                warn!(
+                    "Rejecting PTY request for channel {:?}: {}x{} is below minimum {}x{} from {}",
+                    channel,
+                    col_width,
+                    row_height,
+                    self.session_config.min_cols,
+                    self.session_config.min_rows,
+                    self.client_addr
+                );
+                let msg = format!(
+                    "\r\nTerminal too small: {}x{} (minimum {}x{}). Please resize and reconnect.\r\n",
+                    col_width, row_height, self.session_config.min_cols, self.session_config.min_rows
+                );
+                session.data(channel, CryptoVec::from_slice(msg.as_bytes()))?;
+                session.channel_failure(channel)?;
+                session.close(channel)?;
+                return Ok(());
+            }
+
+            let (cols, rows) = self.clamp_pty_size(col_width, row_height);
+            debug!(
+                "PTY request for channel {:?}: {}x{} (requested {}x{}, {} modes) from {}",
+                channel,
+                cols,
+                rows,
+                col_width,
+                row_height,
+                modes.len(),
+                self.client_addr
+            );
+            let term: String = term
+                .chars()
+                .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '+'))
+                .take(64)
+                .collect();
+            self.log_event(format_args!(
+                "pty {} {}x{} (requested {}x{}, {} modes)",
+                term,
+                cols,
+                rows,
+                col_width,
+                row_height,
+                modes.len()
+            ));
+            self.pty_requested = true;
+            self.terminals.insert(
+                channel,
+                Terminal {
+                    size: (cols, rows),
+                    modes: modes.to_vec(),
+                    term,
+                },
+            );
+            session.channel_success(channel)?;
+            Ok(())
+        }
+        .instrument(span)
+        .await
     }
 
     async fn shell_request(
@@ -130,124 +1332,571 @@ impl Handler for SessionHandler {
         channel: ChannelId,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
-        if self.shell_requested {
-            error!(
-                "SECURITY: duplicate shell request from {} - disconnecting",
-                self.client_addr
+        let span = self.channel_span(channel);
+        async move {
+            if self.shell_channels.contains(&channel)
+                || (self.shell_requested && !self.session_config.multi_shell)
+            {
+                error!(
+                    "SECURITY: duplicate shell request from {} - disconnecting",
+                    self.client_addr
+                );
+                session.disconnect(Disconnect::ByApplication, "duplicate shell request", "en")?;
+                return Ok(());
+            }
+            self.shell_requested = true;
+            self.shell_channels.insert(channel);
+
+            info!(
+                "Shell request for channel {:?} from {}",
+                channel, self.client_addr
             );
-            session.disconnect(Disconnect::ByApplication, "duplicate shell request", "en")?;
-            return Ok(());
-        }
-        self.shell_requested = true;
 
-        info!(
-            "Shell request for channel {:?} from {}",
-            channel, self.client_addr
-        );
+            if self.session_config.require_pty && !self.terminals.contains_key(&channel) {
+                warn!(
+                    "Refusing shell without a PTY for channel {:?} from {}",
+                    channel, self.client_addr
+                );
+                self.refuse_shell(channel, session, "A PTY is required; connect with `ssh -t`.")?;
+                return Ok(());
+            }
+
+            if let Some(single) = &self.session_config.single_session
+                && !self.single_session_claimed
+            {
+                if !single.claim() {
+                    info!(
+                        "Refusing shell for {}: the single session is taken",
+                        self.client_addr
+                    );
+                    self.refuse_shell(channel, session, "This server runs a single session, which is taken.")?;
+                    return Ok(());
+                }
+                self.single_session_claimed = true;
+            }
 
-        let (cols, rows) = self.pty_size;
-        let pty = match PtySession::spawn(
-            &self.tui_config.command,
-            &self.tui_config.args,
-            &self.tui_config.env,
-            cols,
-            rows,
-        ) {
-            Ok(pty) => pty,
-            Err(e) => {
-                error!("Failed to spawn PTY for {}: {}", self.client_addr, e);
+            self.resolve_client_host().await;
+            let Some(command) = self.session_command(channel, self.client_host.clone()) else {
+                error!("No command for session from {}", self.client_addr);
                 session.channel_failure(channel)?;
                 return Ok(());
+            };
+
+            if self.session_config.shared_sessions
+                && let Some(user) = &self.session.user
+                && let Some(shared) = self.registry.get(user)
+            {
+                info!(
+                    "Attaching {} to the running session for {} as a viewer",
+                    self.client_addr, user
+                );
+                session.channel_success(channel)?;
+                shared
+                    .attach(
+                        self.session.id,
+                        session.handle(),
+                        channel,
+                        self.terminal(channel).size,
+                    )
+                    .await;
+                self.shared = Some(shared);
+                self.viewer = true;
+                self.broadcaster
+                    .register(self.session.id, channel, session.handle());
+                return Ok(());
             }
-        };
 
-        session.channel_success(channel)?;
+            let Some(pty_slot) = PtySlot::acquire(&self.active_ptys, self.session_config.max_ptys)
+            else {
+                warn!(
+                    "PTY limit reached ({}), rejecting shell from {}",
+                    self.session_config.max_ptys, self.client_addr
+                );
+                self.refuse_shell(channel, session, &self.session_config.pty_error_message)?;
+                return Ok(());
+            };
+
+            let terminal = self.terminal(channel);
+            let (cols, rows) = terminal.size;
+            let mut respawner = self
+                .session_config
+                .respawn
+                .clone()
+                .map(|policy| Respawner::new(policy, command.clone(), terminal.modes.clone()));
+            // The welcome command goes first; the main command replaces it on exit
+            let welcome = self.session_config.welcome_command.as_ref().map(|welcome| {
+                let user = self
+                    .session
+                    .user
+                    .as_deref()
+                    .map(sanitize_user)
+                    .unwrap_or_default();
+                CmdConfig {
+                    command: self.expand_placeholders(&welcome.command, &user, &terminal),
+                    args: welcome
+                        .args
+                        .iter()
+                        .map(|arg| self.expand_placeholders(arg, &user, &terminal))
+                        .collect(),
+                    ..command.clone()
+                }
+            });
+            let mut next_command = None;
+            let welcome_pty = welcome.and_then(|welcome| {
+                match PtySession::spawn(&welcome, &terminal.modes, cols, rows) {
+                    Ok(pty) => {
+                        next_command = Some(command.clone());
+                        Some(pty)
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Failed to spawn welcome command for {}, skipping it: {:#}",
+                            self.client_addr, e
+                        );
+                        None
+                    }
+                }
+            });
+            let pty = match welcome_pty.map_or_else(
+                || PtySession::spawn(&command, &terminal.modes, cols, rows),
+                Ok,
+            ) {
+                Ok(pty) => pty,
+                Err(e) => {
+                    error!("Failed to spawn PTY for {}: {}", self.client_addr, e);
+                    if e.is::<PtyUnavailable>() {
+                        self.refuse_shell(channel, session, &self.session_config.pty_error_message)?;
+                    } else {
+                        session.channel_failure(channel)?;
+                    }
+                    return Ok(());
+                }
+            };
+
+            session.channel_success(channel)?;
+            self.shell_started = true;
+            self.log_event(format_args!(
+                "started {} {:?} at {}x{}",
+                command.command, command.args, cols, rows
+            ));
+            self.broadcaster
+                .register(self.session.id, channel, session.handle());
+            if let Some(observer) = &self.observer {
+                observer.on_shell_start(&self.session, &command.command);
+            }
+            if let Some(hooks) = &self.session_config.hooks {
+                hooks.session_started(&self.session, &command.command);
+            }
 
-        let (mut pty_reader, pty_writer) = pty.split();
-        let pty_writer = Arc::new(Mutex::new(pty_writer));
+            // Queued ahead of anything the reader task sends through the handle,
+            // so the MOTD always precedes the command's first output
+            let alt_screen = self.session_config.alt_screen;
+            if alt_screen {
+                session.data(channel, CryptoVec::from_slice(ENTER_ALT_SCREEN))?;
+            }
+            if alt_screen || self.session_config.clear_on_start {
+                session.data(channel, CryptoVec::from_slice(CLEAR_SCREEN))?;
+            }
+            if let Some(motd) = &self.session_config.motd {
+                let text = motd
+                    .text()
+                    .replace("{user}", self.session.user.as_deref().unwrap_or(""))
+                    .replace("{addr}", &self.client_addr);
+                session.data(channel, CryptoVec::from_slice(text.as_bytes()))?;
+            }
 
-        self.pty_writers
-            .lock()
-            .await
-            .insert(channel, pty_writer.clone());
+            let (mut pty_reader, pty_writer) = pty.split();
+            let pty_writer = Arc::new(Mutex::new(pty_writer));
 
-        let handle = session.handle();
-        let client_addr = self.client_addr.clone();
-        let max_session_duration = self.max_session_duration;
+            self.pty_writers
+                .lock()
+                .await
+                .insert(channel, pty_writer.clone());
 
-        tokio::spawn(async move {
-            let read_loop = async {
-                let mut buf = [0u8; 4096];
-                loop {
-                    match pty_reader.read(&mut buf).await {
-                        Ok(0) => {
-                            debug!("PTY closed (EOF) for {}", client_addr);
-                            let _ = handle.close(channel).await;
-                            break;
+            if let Some(interval) = self.session_config.refresh_interval {
+                spawn_refresh_task(Arc::downgrade(&pty_writer), interval);
+            }
+
+            // Goes to the main command, so it waits out a welcome command
+            let initial_input = self.session_config.initial_input.clone();
+            let initial_input_delay = self.session_config.initial_input_delay;
+            if let Some(input) = &initial_input
+                && next_command.is_none()
+                && let Ok(writer) = pty_writer.clone().try_lock_owned()
+            {
+                spawn_initial_input(writer, input.clone(), initial_input_delay);
+            }
+
+            // Later connections for this user attach to it until it ends
+            let shared = match &self.session.user {
+                Some(user) if self.session_config.shared_sessions => {
+                    let shared = Arc::new(SharedSession::new(
+                        self.session.id,
+                        Arc::downgrade(&pty_writer),
+                        (cols, rows),
+                    ));
+                    if self.registry.insert(user, shared.clone()) {
+                        self.shared = Some(shared.clone());
+                        Some((self.registry.clone(), user.clone(), shared))
+                    } else {
+                        debug!("Session for {} already shared, not sharing this one", user);
+                        None
+                    }
+                }
+                _ => None,
+            };
+
+            let handle = session.handle();
+            let client_addr = self.client_addr.clone();
+            let max_session_duration = self.max_session_duration;
+            let motd_delay = self.session_config.motd_delay;
+            let max_output_bytes = self.session_config.max_output_bytes;
+            let max_output_bps = self.session_config.max_output_bps;
+            let debug_io = self.session_config.debug_io;
+            let output_flush_interval = self.session_config.output_flush_interval;
+            let output_flush_bytes = self.session_config.output_flush_bytes;
+            let audit = self.audit.clone();
+            let end_reason = self.end_reason.clone();
+            let session_log = self.session_log.clone();
+            let bytes = self.bytes.clone();
+            let modes = terminal.modes;
+
+            let (closer, closed) = oneshot::channel::<()>();
+            self.pty_closers.insert(channel, closer);
+
+            // Weak so a closed channel's writer isn't kept alive just for respawns
+            let pty_writer = Arc::downgrade(&pty_writer);
+            tokio::spawn(async move {
+                let _pty_slot = pty_slot;
+                let read_loop = async {
+                    // Leave the MOTD on screen before the command draws over it
+                    if let Some(delay) = motd_delay {
+                        tokio::time::sleep(delay).await;
+                    }
+                    let mut buf = [0u8; 4096];
+                    let mut output_bytes = 0u64;
+                    let mut throttle = (max_output_bps > 0).then(|| TokenBucket::new(max_output_bps));
+                    let mut output = OutputBuffer::new(output_flush_interval, output_flush_bytes);
+                    loop {
+                        let read = match output.deadline {
+                            Some(deadline) => {
+                                match tokio::time::timeout_at(deadline, pty_reader.read(&mut buf))
+                                    .await
+                                {
+                                    Ok(read) => read,
+                                    Err(_) => {
+                                        if !output.flush(&handle, channel).await {
+                                            debug!(
+                                                "Failed to send data to channel for {}, closing",
+                                                client_addr
+                                            );
+                                            return SessionEnd::ClientGone;
+                                        }
+                                        continue;
+                                    }
+                                }
+                            }
+                            None => pty_reader.read(&mut buf).await,
+                        };
+                        match read {
+                            Ok(0) => debug!("PTY closed (EOF) for {}", client_addr),
+                            Ok(n) => {
+                                let mut n = n;
+                                let over_limit =
+                                    max_output_bytes > 0 && output_bytes + n as u64 > max_output_bytes;
+                                if over_limit {
+                                    n = (max_output_bytes - output_bytes) as usize;
+                                }
+                                output_bytes += n as u64;
+                                bytes.output.fetch_add(n as u64, Ordering::Relaxed);
+                                if let Some(limit) = debug_io {
+                                    trace!(
+                                        "Output for {} ({} bytes):{}",
+                                        client_addr,
+                                        n,
+                                        hex_dump(&buf[..n], limit)
+                                    );
+                                }
+                                if let Some((_, _, shared)) = &shared {
+                                    shared.broadcast(&buf[..n]);
+                                }
+                                let flush = output.push(&buf[..n]) || over_limit;
+                                if flush && !output.flush(&handle, channel).await {
+                                    debug!(
+                                        "Failed to send data to channel for {}, closing",
+                                        client_addr
+                                    );
+                                    return SessionEnd::ClientGone;
+                                }
+                                if over_limit {
+                                    return SessionEnd::OutputLimit;
+                                }
+                                // Not reading meanwhile lets the tty buffer fill
+                                // up and stall the command
+                                if let Some(throttle) = &mut throttle {
+                                    tokio::time::sleep(throttle.take(n as u64)).await;
+                                }
+                                continue;
+                            }
+                            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                            // Linux reports EIO rather than EOF once the child side closes
+                            Err(e) => debug!("PTY read error for {}: {}", client_addr, e),
                         }
-                        Ok(n) => {
-                            let data = CryptoVec::from_slice(&buf[..n]);
-                            if handle.data(channel, data).await.is_err() {
-                                debug!(
-                                    "Failed to send data to channel for {}, closing",
-                                    client_addr
+                        // The command's last output goes out before anything
+                        // sent about its exit
+                        output.flush(&handle, channel).await;
+
+                        match pty_reader.wait(CHILD_EXIT_WAIT).await {
+                            Ok(Some(status)) => {
+                                info!(
+                                    "Command for {} on {:?} {}",
+                                    client_addr,
+                                    channel,
+                                    describe_exit(status)
+                                );
+                                if let Some(audit) = &audit {
+                                    audit.set_exit_reason(format!("command {}", describe_exit(status)));
+                                }
+                                *end_reason.lock().unwrap() =
+                                    Some(format!("command {}", describe_exit(status)));
+                                if let Some(log) = &session_log {
+                                    log.write(format_args!("command {}", describe_exit(status)));
+                                }
+                            }
+                            Ok(None) => {
+                                warn!(
+                                    "Command for {} on {:?} closed its terminal but is still running, terminating it",
+                                    client_addr, channel
                                 );
-                                break;
+                                if let Err(e) = pty_reader.terminate(CHILD_KILL_GRACE).await {
+                                    debug!("Failed to terminate child for {}: {}", client_addr, e);
+                                }
+                            }
+                            Err(e) => {
+                                debug!("{:#} for {}", e, client_addr);
+                                if let Err(e) = pty_reader.terminate(CHILD_KILL_GRACE).await {
+                                    debug!("Failed to terminate child for {}: {}", client_addr, e);
+                                }
+                            }
+                        }
+
+                        if let Some(command) = next_command.take()
+                            && let Some(pty_writer) = pty_writer.upgrade()
+                        {
+                            match spawn_into(&pty_writer, &command, &modes).await {
+                                Ok(reader) => {
+                                    debug!("Welcome command done, starting {}", command.command);
+                                    if let Some(log) = &session_log {
+                                        log.write(format_args!("started {}", command.command));
+                                    }
+                                    pty_reader = reader;
+                                    if let Some(input) = &initial_input {
+                                        let writer = pty_writer.lock_owned().await;
+                                        spawn_initial_input(writer, input.clone(), initial_input_delay);
+                                    }
+                                    continue;
+                                }
+                                Err(e) => {
+                                    error!("Failed to spawn PTY for {}: {}", client_addr, e);
+                                    restore_screen(&handle, channel, alt_screen).await;
+                                    let _ = handle.close(channel).await;
+                                    return SessionEnd::Exited;
+                                }
                             }
                         }
-                        Err(e) => {
-                            if e.kind() != std::io::ErrorKind::WouldBlock {
-                                debug!("PTY read error for {}: {}", client_addr, e);
-                                let _ = handle.close(channel).await;
-                                break;
+
+                        let mut closing_message = None;
+                        if let Some(respawner) = respawner.as_mut()
+                            && let Some(pty_writer) = pty_writer.upgrade()
+                        {
+                            match respawner.respawn(&pty_writer).await {
+                                Ok(reader) => {
+                                    info!("Respawned command for {}", client_addr);
+                                    if let Some(log) = &session_log {
+                                        log.write(format_args!("respawned command"));
+                                    }
+                                    output_bytes = 0;
+                                    let clear = CryptoVec::from_slice(CLEAR_SCREEN);
+                                    let _ = handle.data(channel, clear).await;
+                                    if let Some((_, _, shared)) = &shared {
+                                        shared.broadcast(CLEAR_SCREEN);
+                                    }
+                                    pty_reader = reader;
+                                    if let Some(input) = &initial_input {
+                                        let writer = pty_writer.lock_owned().await;
+                                        spawn_initial_input(writer, input.clone(), initial_input_delay);
+                                    }
+                                    continue;
+                                }
+                                Err(e) => {
+                                    warn!("Not respawning command for {}: {}", client_addr, e);
+                                    closing_message = Some(format!("\r\nSession closed: {}.\r\n", e));
+                                }
                             }
                         }
+                        restore_screen(&handle, channel, alt_screen).await;
+                        if let Some(msg) = closing_message {
+                            let _ = handle
+                                .data(channel, CryptoVec::from_slice(msg.as_bytes()))
+                                .await;
+                        }
+                        let _ = handle.close(channel).await;
+                        return SessionEnd::Exited;
+                    }
+                };
+
+                let deadline = async {
+                    match max_session_duration {
+                        Some(duration) => {
+                            tokio::time::sleep(duration).await;
+                            duration
+                        }
+                        None => std::future::pending().await,
                     }
+                };
+
+                let end = tokio::select! {
+                    end = read_loop => end,
+                    duration = deadline => SessionEnd::Expired(duration),
+                    _ = closed => SessionEnd::ClientGone,
+                };
+
+                if let Some((registry, user, shared)) = &shared {
+                    registry.remove(user, shared);
+                    shared.close("\r\nShared session ended.\r\n");
                 }
-            };
 
-            if let Some(duration) = max_session_duration {
-                if tokio::time::timeout(duration, read_loop).await.is_err() {
-                    info!(
-                        "Max session duration ({:?}) exceeded for {} - disconnecting",
-                        duration, client_addr
-                    );
-                    let msg = format!(
-                        "\r\nSession terminated: max session duration ({}s) exceeded.\r\n",
-                        duration.as_secs()
-                    );
-                    let _ = handle
-                        .data(channel, CryptoVec::from_slice(msg.as_bytes()))
-                        .await;
-                    let _ = handle
-                        .disconnect(
-                            Disconnect::ByApplication,
-                            "max session duration exceeded".to_string(),
-                            "en".to_string(),
-                        )
-                        .await;
+                let reason = match &end {
+                    SessionEnd::Exited => None,
+                    SessionEnd::ClientGone => Some("client disconnected"),
+                    SessionEnd::OutputLimit => Some("output limit reached"),
+                    SessionEnd::Expired(_) => Some("session time limit reached"),
+                };
+                if let Some(reason) = reason {
+                    if let Some(audit) = &audit {
+                        audit.set_exit_reason(reason);
+                    }
+                    *end_reason.lock().unwrap() = Some(reason.to_string());
+                    if let Some(log) = &session_log {
+                        log.write(format_args!("ending: {}", reason));
+                    }
+                }
+
+                match end {
+                    SessionEnd::Exited => {}
+                    SessionEnd::ClientGone => {
+                        debug!("Channel closed for {}, terminating command", client_addr);
+                        if let Err(e) = pty_reader.terminate(CHILD_KILL_GRACE).await {
+                            debug!("Failed to terminate child for {}: {}", client_addr, e);
+                        }
+                    }
+                    SessionEnd::OutputLimit => {
+                        warn!(
+                            "Output limit ({} bytes) reached for {} - closing session",
+                            max_output_bytes, client_addr
+                        );
+                        let msg = format!(
+                            "\r\nSession terminated: output limit ({} bytes) reached.\r\n",
+                            max_output_bytes
+                        );
+                        restore_screen(&handle, channel, alt_screen).await;
+                        let _ = handle
+                            .data(channel, CryptoVec::from_slice(msg.as_bytes()))
+                            .await;
+                        if let Err(e) = pty_reader.terminate(CHILD_KILL_GRACE).await {
+                            debug!("Failed to terminate child for {}: {}", client_addr, e);
+                        }
+                        let _ = handle.close(channel).await;
+                    }
+                    SessionEnd::Expired(duration) => {
+                        info!(
+                            "Max session duration ({:?}) exceeded for {} - closing session",
+                            duration, client_addr
+                        );
+                        let msg = format!(
+                            "\r\nSession terminated: max session duration ({}s) exceeded.\r\n",
+                            duration.as_secs()
+                        );
+                        restore_screen(&handle, channel, alt_screen).await;
+                        let _ = handle
+                            .data(channel, CryptoVec::from_slice(msg.as_bytes()))
+                            .await;
+                        if let Err(e) = pty_reader.terminate(CHILD_KILL_GRACE).await {
+                            debug!("Failed to terminate child for {}: {}", client_addr, e);
+                        }
+                        let _ = handle
+                            .disconnect(
+                                Disconnect::ByApplication,
+                                "session time limit reached".to_string(),
+                                "en".to_string(),
+                            )
+                            .await;
+                    }
                 }
-            } else {
-                read_loop.await;
             }
-        });
+            .in_current_span());
 
-        Ok(())
+            Ok(())
+        }
+        .instrument(span)
+        .await
     }
 
     async fn data(
         &mut self,
         channel: ChannelId,
         data: &[u8],
-        _session: &mut Session,
+        session: &mut Session,
     ) -> Result<(), Self::Error> {
-        if let Some(writer) = self.pty_writers.lock().await.get(&channel) {
-            let mut writer = writer.lock().await;
-            if let Err(e) = writer.write_all(data).await {
-                warn!("Failed to write to PTY for {}: {}", self.client_addr, e);
+        let span = self.channel_span(channel);
+        async move {
+            self.bytes
+                .input
+                .fetch_add(data.len() as u64, Ordering::Relaxed);
+            if self.readonly || self.viewer {
+                return Ok(());
+            }
+            // Cloned out so a stalled write doesn't hold up the other channels
+            let writer = self.pty_writers.lock().await.get(&channel).cloned();
+            let Some(writer) = writer else {
+                return Ok(());
+            };
+            if let Some(limit) = self.session_config.debug_io {
+                trace!(
+                    "Input for {} ({} bytes):{}",
+                    self.client_addr,
+                    data.len(),
+                    hex_dump(data, limit)
+                );
+            }
+            let timeout = self.session_config.stdin_timeout;
+            if let Some(throttle) = &self.input_throttle {
+                let queue = self.input_queues.entry(channel).or_insert_with(|| {
+                    spawn_input_task(
+                        self.pty_writers.clone(),
+                        channel,
+                        throttle.clone(),
+                        timeout,
+                        self.client_addr.clone(),
+                    )
+                });
+                if !queue.try_send(data) {
+                    warn!(
+                        "Input for {} is over {} bytes behind --max-input-bps, closing the channel",
+                        self.client_addr, INPUT_QUEUE_BYTES
+                    );
+                    self.input_queues.remove(&channel);
+                    // Dropping the closer terminates the command
+                    self.pty_closers.remove(&channel);
+                    session.close(channel)?;
+                }
+            } else {
+                write_input(&writer, data, timeout, &self.client_addr).await;
             }
+            Ok(())
         }
-        Ok(())
+        .instrument(span)
+        .await
     }
 
     async fn window_change_request(
@@ -259,19 +1908,36 @@ impl Handler for SessionHandler {
         _pix_height: u32,
         _session: &mut Session,
     ) -> Result<(), Self::Error> {
-        let (cols, rows) = Self::clamp_pty_size(col_width, row_height);
-        debug!(
-            "Window change for channel {:?}: {}x{} from {}",
-            channel, cols, rows, self.client_addr
-        );
+        let span = self.channel_span(channel);
+        async move {
+            let (cols, rows) = self.clamp_pty_size(col_width, row_height);
+            debug!(
+                "Window change for channel {:?}: {}x{} from {}",
+                channel, cols, rows, self.client_addr
+            );
+
+            // Remembered even before the shell exists, so it spawns at this size
+            if let Some(terminal) = self.terminals.get_mut(&channel) {
+                terminal.size = (cols, rows);
+            }
+            self.log_event(format_args!("resize {}x{}", cols, rows));
+
+            if let Some(shared) = &self.shared {
+                shared.resize(self.session.id, (cols, rows)).await;
+                return Ok(());
+            }
 
-        if let Some(writer) = self.pty_writers.lock().await.get(&channel) {
-            let writer = writer.lock().await;
-            if let Err(e) = writer.resize(cols, rows) {
-                warn!("Failed to resize PTY for {}: {}", self.client_addr, e);
+            let writer = self.pty_writers.lock().await.get(&channel).cloned();
+            if let Some(writer) = writer {
+                let mut writer = writer.lock().await;
+                if let Err(e) = writer.resize(cols, rows) {
+                    warn!("Failed to resize PTY for {}: {}", self.client_addr, e);
+                }
             }
+            Ok(())
         }
-        Ok(())
+        .instrument(span)
+        .await
     }
 
     async fn channel_close(
@@ -279,9 +1945,27 @@ impl Handler for SessionHandler {
         channel: ChannelId,
         _session: &mut Session,
     ) -> Result<(), Self::Error> {
-        debug!("Channel close: {:?} from {}", channel, self.client_addr);
-        self.pty_writers.lock().await.remove(&channel);
-        Ok(())
+        let span = self.channel_span(channel);
+        async move {
+            debug!("Channel close: {:?} from {}", channel, self.client_addr);
+            self.open_channels = self.open_channels.saturating_sub(1);
+            self.pty_writers.lock().await.remove(&channel);
+            self.pty_closers.remove(&channel);
+            self.input_queues.remove(&channel);
+            self.client_env.remove(&channel);
+            self.terminals.remove(&channel);
+            self.shell_channels.remove(&channel);
+            self.channel_spans.remove(&channel);
+            self.broadcaster.unregister(self.session.id, channel);
+            if self.viewer
+                && let Some(shared) = &self.shared
+            {
+                shared.detach(self.session.id);
+            }
+            Ok(())
+        }
+        .instrument(span)
+        .await
     }
 
     async fn channel_eof(
@@ -289,8 +1973,13 @@ impl Handler for SessionHandler {
         channel: ChannelId,
         _session: &mut Session,
     ) -> Result<(), Self::Error> {
-        debug!("Channel EOF: {:?} from {}", channel, self.client_addr);
-        Ok(())
+        let span = self.channel_span(channel);
+        async move {
+            debug!("Channel EOF: {:?} from {}", channel, self.client_addr);
+            Ok(())
+        }
+        .instrument(span)
+        .await
     }
 
     // =========================================================================
@@ -298,65 +1987,115 @@ impl Handler for SessionHandler {
     //
     // ALLOWED (implemented above):
     //   - pty_request: Terminal allocation
-    //   - shell_request: Spawn TUI (once per session)
+    //   - shell_request: Spawn TUI (once per session, or per channel with --multi-shell)
     //   - window_change_request: Terminal resize
     //   - channel_open_session: Session channel
     //   - data: stdin to PTY
+    //   - env_request: only names on the --allow-env list (others ignored below)
+    //   - signal: only with --forward-signals (others ignored below)
     //   - channel_close, channel_eof: Cleanup
     //
     // EXPLICITLY REJECTED (below):
     //   Everything else is explicitly rejected with logging.
     //   This ensures new russh features don't accidentally get allowed.
+    //
+    // NOT SEEN HERE:
+    //   Global requests russh has no handler method for, such as
+    //   no-more-sessions@openssh.com, are refused by russh before reaching
+    //   us. The extra sessions that request guards against are already
+    //   refused by max_channels, which defaults to one per connection.
     // =========================================================================
 
     async fn exec_request(
         &mut self,
-        _channel: ChannelId,
+        channel: ChannelId,
         data: &[u8],
         session: &mut Session,
     ) -> Result<(), Self::Error> {
-        let cmd = String::from_utf8_lossy(data);
-        error!(
-            "SECURITY: exec request from {}: {:?} - disconnecting client",
-            self.client_addr,
-            cmd.chars().take(100).collect::<String>()
-        );
-        // Disconnect the client immediately
-        session.disconnect(Disconnect::ByApplication, "exec not permitted", "en")?;
-        Ok(())
+        let span = self.channel_span(channel);
+        async move {
+            let cmd = String::from_utf8_lossy(data);
+            error!(
+                "SECURITY: exec request from {}: {:?} - disconnecting client",
+                self.client_addr,
+                cmd.chars().take(100).collect::<String>()
+            );
+            // Disconnect the client immediately
+            session.disconnect(Disconnect::ByApplication, "exec not permitted", "en")?;
+            Ok(())
+        }
+        .instrument(span)
+        .await
     }
 
     async fn subsystem_request(
         &mut self,
-        _channel: ChannelId,
+        channel: ChannelId,
         name: &str,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
-        error!(
-            "SECURITY: subsystem request '{}' from {} - disconnecting",
-            name, self.client_addr
-        );
-        session.disconnect(Disconnect::ByApplication, "subsystem not permitted", "en")?;
-        Ok(())
+        let span = self.channel_span(channel);
+        async move {
+            error!(
+                "SECURITY: subsystem request '{}' from {} - disconnecting",
+                name, self.client_addr
+            );
+            session.disconnect(Disconnect::ByApplication, "subsystem not permitted", "en")?;
+            Ok(())
+        }
+        .instrument(span)
+        .await
     }
 
     async fn env_request(
         &mut self,
-        _channel: ChannelId,
+        channel: ChannelId,
         variable_name: &str,
         variable_value: &str,
         _session: &mut Session,
     ) -> Result<(), Self::Error> {
-        // Env requests are commonly sent by SSH clients (TERM, LANG, etc.)
-        // Just ignore them - don't even send failure response as it can cause issues
-        debug!(
-            "Ignoring env request {}={} from {}",
-            variable_name,
-            variable_value.chars().take(50).collect::<String>(),
-            self.client_addr
-        );
-        // Note: Not sending channel_failure - just silently ignore
-        Ok(())
+        let span = self.channel_span(channel);
+        async move {
+            if self
+                .session_config
+                .allow_env
+                .iter()
+                .any(|name| name == variable_name)
+            {
+                let env = self.client_env.entry(channel).or_default();
+                if variable_value.len() > MAX_CLIENT_ENV_VALUE_LEN
+                    || variable_value.contains('\0')
+                    || (env.len() >= MAX_CLIENT_ENV_VARS
+                        && !env.iter().any(|(key, _)| key == variable_name))
+                {
+                    warn!(
+                        "Dropping env request {} from {}: value or count limit exceeded",
+                        variable_name, self.client_addr
+                    );
+                    return Ok(());
+                }
+                debug!(
+                    "Accepting env request {} from {}",
+                    variable_name, self.client_addr
+                );
+                env.retain(|(key, _)| key != variable_name);
+                env.push((variable_name.to_string(), variable_value.to_string()));
+                return Ok(());
+            }
+
+            // Env requests are commonly sent by SSH clients (TERM, LANG, etc.)
+            // Ignore the ones not on the allowlist - don't even send failure response as it can cause issues
+            debug!(
+                "Ignoring env request {}={} from {}",
+                variable_name,
+                variable_value.chars().take(50).collect::<String>(),
+                self.client_addr
+            );
+            // Note: Not sending channel_failure - just silently ignore
+            Ok(())
+        }
+        .instrument(span)
+        .await
     }
 
     async fn x11_request(
@@ -375,13 +2114,38 @@ impl Handler for SessionHandler {
 
     async fn signal(
         &mut self,
-        _channel: ChannelId,
+        channel: ChannelId,
         signal: russh::Sig,
         _session: &mut Session,
     ) -> Result<(), Self::Error> {
-        // Signals can be legitimate (e.g., window resize sends SIGWINCH)
-        debug!("Ignoring signal {:?} from {}", signal, self.client_addr);
-        Ok(())
+        let span = self.channel_span(channel);
+        async move {
+            let target = unix_signal(&signal);
+            if !self.session_config.forward_signals || self.readonly || self.viewer {
+                debug!("Ignoring signal {:?} from {}", signal, self.client_addr);
+                return Ok(());
+            }
+            let Some(target) = target else {
+                debug!(
+                    "Ignoring unsupported signal {:?} from {}",
+                    signal, self.client_addr
+                );
+                return Ok(());
+            };
+            info!(
+                "Forwarding {} from {} to its command",
+                target, self.client_addr
+            );
+            let writer = self.pty_writers.lock().await.get(&channel).cloned();
+            if let Some(writer) = writer
+                && let Err(e) = writer.lock().await.signal(target)
+            {
+                debug!("{:#}", e);
+            }
+            Ok(())
+        }
+        .instrument(span)
+        .await
     }
 
     async fn tcpip_forward(
@@ -494,3 +2258,390 @@ impl Handler for SessionHandler {
         Ok(false)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AuthFuture;
+    use crate::server::{TuiSshServer, generate_host_key};
+    use crate::testing::{TestServer, reaped_within};
+    use russh::keys::{Algorithm, PublicKey};
+    use std::net::IpAddr;
+    use std::time::SystemTime;
+
+    const CLIENT: &str = "192.0.2.7:40000";
+
+    // Accepts one key and the password "secret"
+    struct TestAuthenticator(PublicKey);
+
+    impl Authenticator for TestAuthenticator {
+        fn authenticate_password<'a>(
+            &'a self,
+            _user: &'a str,
+            password: &'a str,
+        ) -> AuthFuture<'a> {
+            Box::pin(async move { password == "secret" })
+        }
+
+        fn authenticate_publickey<'a>(
+            &'a self,
+            _user: &'a str,
+            key: &'a PublicKey,
+        ) -> AuthFuture<'a> {
+            Box::pin(async move { *key == self.0 })
+        }
+    }
+
+    fn public_key() -> PublicKey {
+        generate_host_key(Algorithm::Ed25519)
+            .unwrap()
+            .public_key()
+            .clone()
+    }
+
+    fn handler(ban_list: &Arc<BanList>, key: PublicKey) -> SessionHandler {
+        let commands = CommandMap {
+            default: Some(CmdConfig::new("true")),
+            users: HashMap::new(),
+            user_env: HashMap::new(),
+            apps: HashMap::new(),
+        };
+        let session = SessionInfo {
+            id: 1,
+            addr: Some(CLIENT.parse().unwrap()),
+            user: None,
+            connected_at: SystemTime::now(),
+        };
+        let mut handler = SessionHandler::new(
+            Arc::new(commands),
+            Arc::new(SessionConfig::default()),
+            session,
+            Arc::new(AtomicUsize::new(1)),
+            Arc::new(AtomicUsize::new(0)),
+            None,
+            Some(ban_list.clone()),
+        );
+        handler.set_authenticator(Arc::new(TestAuthenticator(key)));
+        handler
+    }
+
+    fn client_ip() -> IpAddr {
+        CLIENT.parse::<SocketAddr>().unwrap().ip()
+    }
+
+    fn ban_list() -> Arc<BanList> {
+        Arc::new(BanList::new(
+            3,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        ))
+    }
+
+    #[tokio::test]
+    async fn offered_keys_are_not_counted_as_failures() {
+        let ban_list = ban_list();
+        let mut handler = handler(&ban_list, public_key());
+        for _ in 0..5 {
+            let auth = handler
+                .auth_publickey_offered("alice", &public_key())
+                .await
+                .unwrap();
+            assert!(matches!(auth, Auth::Reject { .. }));
+        }
+        assert!(!ban_list.is_banned(client_ip()));
+    }
+
+    #[tokio::test]
+    async fn offering_the_right_key_asks_for_a_signature() {
+        let key = public_key();
+        let mut handler = handler(&ban_list(), key.clone());
+        let auth = handler.auth_publickey_offered("alice", &key).await.unwrap();
+        assert_eq!(auth, Auth::Accept);
+        assert_eq!(handler.session.user, None);
+    }
+
+    #[tokio::test]
+    async fn none_probes_are_not_counted_as_failures() {
+        let ban_list = ban_list();
+        let mut handler = handler(&ban_list, public_key());
+        for _ in 0..5 {
+            handler.auth_none("alice").await.unwrap();
+        }
+        assert!(!ban_list.is_banned(client_ip()));
+    }
+
+    #[tokio::test]
+    async fn signed_keys_that_are_refused_are_counted() {
+        let ban_list = ban_list();
+        let mut handler = handler(&ban_list, public_key());
+        for _ in 0..3 {
+            handler
+                .auth_publickey("alice", &public_key())
+                .await
+                .unwrap();
+        }
+        assert!(ban_list.is_banned(client_ip()));
+    }
+
+    #[tokio::test]
+    async fn wrong_passwords_are_counted() {
+        let ban_list = ban_list();
+        let mut handler = handler(&ban_list, public_key());
+        for _ in 0..2 {
+            handler.auth_password("alice", "guess").await.unwrap();
+        }
+        assert!(!ban_list.is_banned(client_ip()));
+        handler.auth_password("alice", "guess").await.unwrap();
+        assert!(ban_list.is_banned(client_ip()));
+    }
+
+    #[tokio::test]
+    async fn right_password_is_accepted() {
+        let ban_list = ban_list();
+        let mut handler = handler(&ban_list, public_key());
+        let auth = handler.auth_password("alice", "secret").await.unwrap();
+        assert_eq!(auth, Auth::Accept);
+        assert_eq!(handler.session.user.as_deref(), Some("alice"));
+    }
+
+    // Runs `script` with sh; test scripts print `pid=$$` first
+    fn sh(script: &str) -> CmdConfig {
+        let mut config = CmdConfig::new("sh");
+        config.args = vec!["-c".to_string(), script.to_string()];
+        config
+    }
+
+    #[tokio::test]
+    async fn sessions_end_at_the_max_duration() {
+        let server = TuiSshServer::builder()
+            .command(sh("echo pid=$$; exec sleep 100"))
+            .session_duration(Duration::from_secs(1))
+            .build()
+            .unwrap();
+        let server = TestServer::start(server).await;
+        let mut shell = server.shell("alice").await;
+        let pid = shell.read_pid().await;
+        let started = Instant::now();
+        shell.read_to_end().await;
+        assert!(started.elapsed() >= Duration::from_millis(900));
+        assert!(
+            shell
+                .text()
+                .contains("Session terminated: max session duration (1s) exceeded."),
+            "{:?}",
+            shell.text()
+        );
+        assert!(reaped_within(pid, CHILD_KILL_GRACE).await);
+    }
+
+    #[tokio::test]
+    async fn time_limit_holds_under_continuous_output() {
+        let server = TuiSshServer::builder()
+            .command(sh("echo pid=$$; while :; do echo tick; sleep 0.05; done"))
+            .session_duration(Duration::from_secs(1))
+            .build()
+            .unwrap();
+        let server = TestServer::start(server).await;
+        let mut shell = server.shell("alice").await;
+        let pid = shell.read_pid().await;
+        let started = Instant::now();
+        shell.read_to_end().await;
+        assert!(started.elapsed() < Duration::from_secs(3));
+        assert!(shell.text().matches("tick").count() > 5);
+        assert!(shell.text().contains("max session duration (1s) exceeded"));
+        assert!(reaped_within(pid, CHILD_KILL_GRACE).await);
+    }
+
+    #[tokio::test]
+    async fn sessions_without_a_time_limit_run_to_completion() {
+        let server = TuiSshServer::builder()
+            .command(sh("sleep 1.5; echo finished"))
+            .build()
+            .unwrap();
+        let server = TestServer::start(server).await;
+        let mut shell = server.shell("alice").await;
+        shell.read_to_end().await;
+        assert!(shell.text().contains("finished"));
+        assert!(!shell.text().contains("Session terminated"));
+    }
+
+    #[tokio::test]
+    async fn closing_the_channel_terminates_the_child() {
+        let server = TuiSshServer::builder()
+            .command(sh("echo pid=$$; exec sleep 100"))
+            .build()
+            .unwrap();
+        let server = TestServer::start(server).await;
+        let mut shell = server.shell("alice").await;
+        let pid = shell.read_pid().await;
+        shell.channel.close().await.unwrap();
+        assert!(reaped_within(pid, Duration::from_secs(1)).await);
+    }
+
+    #[tokio::test]
+    async fn children_that_ignore_hangups_are_killed_after_the_grace() {
+        let server = TuiSshServer::builder()
+            .command(sh(
+                "trap '' HUP TERM; echo pid=$$; while :; do sleep 0.1; done",
+            ))
+            .build()
+            .unwrap();
+        let server = TestServer::start(server).await;
+        let mut shell = server.shell("alice").await;
+        let pid = shell.read_pid().await;
+        shell.channel.close().await.unwrap();
+        assert!(!reaped_within(pid, CHILD_KILL_GRACE / 2).await);
+        assert!(reaped_within(pid, CHILD_KILL_GRACE).await);
+    }
+
+    #[tokio::test]
+    async fn commands_that_exit_leave_no_zombie() {
+        let server = TuiSshServer::builder()
+            .command(sh("echo pid=$$"))
+            .build()
+            .unwrap();
+        let server = TestServer::start(server).await;
+        let mut shell = server.shell("alice").await;
+        let pid = shell.read_pid().await;
+        shell.read_to_end().await;
+        assert!(reaped_within(pid, Duration::from_secs(1)).await);
+    }
+
+    #[tokio::test]
+    async fn window_changes_before_the_shell_set_its_size() {
+        let server = TuiSshServer::builder()
+            .command(sh("stty size"))
+            .build()
+            .unwrap();
+        let server = TestServer::start(server).await;
+        let mut shell = server.open("alice", 80, 24).await;
+        shell.channel.window_change(120, 40, 0, 0).await.unwrap();
+        shell.start().await;
+        shell.read_to_end().await;
+        assert_eq!(shell.text().trim(), "40 120");
+    }
+
+    #[tokio::test]
+    async fn input_for_a_command_that_stops_reading_is_dropped() {
+        let session_config = SessionConfig {
+            stdin_timeout: Duration::from_millis(50),
+            ..SessionConfig::default()
+        };
+        let server = TuiSshServer::builder()
+            .command(sh(
+                "stty raw -echo; trap 'echo resized' WINCH; echo pid=$$; while :; do sleep 0.1; done",
+            ))
+            .session_config(session_config)
+            .build()
+            .unwrap();
+        let server = TestServer::start(server).await;
+        let mut shell = server.shell("alice").await;
+        shell.read_pid().await;
+        // Far more than the terminal buffers; the resize is only seen once
+        // the server is done with the input
+        let data = vec![b'x'; 1 << 18];
+        shell.channel.data(&data[..]).await.unwrap();
+        shell.channel.window_change(100, 30, 0, 0).await.unwrap();
+        shell.read_until("resized").await;
+    }
+
+    #[tokio::test]
+    async fn output_is_shaped_to_the_rate() {
+        let session_config = SessionConfig {
+            max_output_bps: 20_000,
+            ..SessionConfig::default()
+        };
+        let server = TuiSshServer::builder()
+            .command(sh("head -c 60000 /dev/zero"))
+            .session_config(session_config)
+            .build()
+            .unwrap();
+        let server = TestServer::start(server).await;
+        let started = Instant::now();
+        let mut shell = server.shell("alice").await;
+        shell.read_to_end().await;
+        let elapsed = started.elapsed();
+        assert!(shell.output.len() >= 60_000);
+        // A second's worth goes out at once, the other 40KB at 20KB/s
+        assert!(
+            elapsed >= Duration::from_millis(1800) && elapsed < Duration::from_secs(4),
+            "took {:?}",
+            elapsed
+        );
+    }
+
+    #[tokio::test]
+    async fn no_zombies_accumulate_over_many_sessions() {
+        let server = TuiSshServer::builder()
+            .command(sh("echo pid=$$; exec sleep 100"))
+            .user_command("quick", sh("echo pid=$$"))
+            .build()
+            .unwrap();
+        let server = TestServer::start(server).await;
+        let mut pids = Vec::new();
+        for i in 0..30 {
+            // Alternates the command exiting, the channel closing and the
+            // client going away
+            let user = if i % 3 == 0 { "quick" } else { "alice" };
+            let mut shell = server.shell(user).await;
+            pids.push(shell.read_pid().await);
+            match i % 3 {
+                0 => shell.read_to_end().await,
+                1 => shell.channel.close().await.unwrap(),
+                _ => drop(shell),
+            }
+        }
+        for pid in pids {
+            assert!(reaped_within(pid, CHILD_KILL_GRACE).await, "pid {}", pid);
+        }
+    }
+
+    #[tokio::test]
+    async fn user_env_overrides_the_base_only_for_that_user() {
+        let command = CmdConfig::builder()
+            .command("sh")
+            .arg("-c")
+            .arg("echo \"$GREETING $NAME\"")
+            .env("GREETING", "hello")
+            .env("NAME", "everyone")
+            .build()
+            .unwrap();
+        let commands = CommandMap {
+            default: Some(command),
+            users: HashMap::new(),
+            user_env: HashMap::from([(
+                "alice".to_string(),
+                vec![("NAME".to_string(), "alice".to_string())],
+            )]),
+            apps: HashMap::new(),
+        };
+        let server = TuiSshServer::builder().commands(commands).build().unwrap();
+        let server = TestServer::start(server).await;
+        for (user, expected) in [("alice", "hello alice"), ("bob", "hello everyone")] {
+            let mut shell = server.shell(user).await;
+            shell.read_to_end().await;
+            assert_eq!(shell.text().trim(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn input_far_behind_the_rate_closes_the_channel() {
+        let session_config = SessionConfig {
+            max_input_bps: 1000,
+            ..SessionConfig::default()
+        };
+        let server = TuiSshServer::builder()
+            .command(sh("stty raw -echo; echo pid=$$; exec cat >/dev/null"))
+            .session_config(session_config)
+            .build()
+            .unwrap();
+        let server = TestServer::start(server).await;
+        let mut shell = server.shell("alice").await;
+        let pid = shell.read_pid().await;
+        let data = vec![b'x'; 2 * INPUT_QUEUE_BYTES];
+        // May not finish: the server stops taking input once it closes
+        let _ = tokio::time::timeout(Duration::from_secs(2), shell.channel.data(&data[..])).await;
+        shell.read_to_end().await;
+        assert!(reaped_within(pid, CHILD_KILL_GRACE).await);
+    }
+}
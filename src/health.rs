@@ -0,0 +1,63 @@
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+// Longest a probe may take to send its request before it's dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Minimal HTTP endpoint for liveness (`/healthz`) and readiness (`/readyz`) probes.
+pub async fn serve(listen: &str, draining: Arc<AtomicBool>) -> Result<()> {
+    let listener = TcpListener::bind(listen)
+        .await
+        .with_context(|| format!("Failed to bind health endpoint to {}", listen))?;
+    info!("Health endpoint listening on {}", listen);
+
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    // Usually fd exhaustion; back off instead of spinning
+                    warn!("Failed to accept health check connection: {}", e);
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+            let draining = draining.clone();
+            tokio::spawn(async move {
+                if let Err(e) = respond(stream, &draining).await {
+                    debug!("Health check request failed: {}", e);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+async fn respond(mut stream: TcpStream, draining: &AtomicBool) -> std::io::Result<()> {
+    let mut buf = [0u8; 1024];
+    let n = tokio::time::timeout(REQUEST_TIMEOUT, stream.read(&mut buf)).await??;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+
+    let status = match path {
+        "/healthz" => "200 OK",
+        "/readyz" if draining.load(Ordering::SeqCst) => "503 Service Unavailable",
+        "/readyz" => "200 OK",
+        _ => "404 Not Found",
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+        status,
+        status.len() + 1,
+        status
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
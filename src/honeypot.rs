@@ -0,0 +1,113 @@
+use crate::audit::unix_now;
+use crate::rate::TokenBucket;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+/// Append-only file with one JSON line per credential tried against the
+/// server in honeypot mode. Kept apart from the server log so it can be
+/// shipped to its own sink. Attempts beyond the rate limit are dropped.
+#[derive(Debug)]
+pub struct HoneypotLog {
+    file: Mutex<File>,
+    limiter: Mutex<WriteLimiter>,
+}
+
+#[derive(Debug)]
+struct WriteLimiter {
+    bucket: TokenBucket,
+    dropped: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Attempt<'a> {
+    /// Unix seconds
+    time: u64,
+    session_id: u64,
+    client: Option<String>,
+    user: &'a str,
+    method: &'a str,
+    password: Option<&'a str>,
+    /// SHA256 fingerprint of an offered public key
+    key: Option<&'a str>,
+}
+
+impl HoneypotLog {
+    /// `max_per_sec` caps lines written per second.
+    pub fn open(path: &Path, max_per_sec: u32) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open honeypot log {}", path.display()))?;
+        Ok(Self {
+            file: Mutex::new(file),
+            limiter: Mutex::new(WriteLimiter {
+                bucket: TokenBucket::new(max_per_sec.into()),
+                dropped: 0,
+            }),
+        })
+    }
+
+    pub(crate) fn record(
+        &self,
+        session_id: u64,
+        client: Option<SocketAddr>,
+        user: &str,
+        method: &str,
+        password: Option<&str>,
+        key: Option<&str>,
+    ) {
+        if !self.take_token() {
+            return;
+        }
+        let attempt = Attempt {
+            time: unix_now(),
+            session_id,
+            client: client.map(|addr| addr.to_string()),
+            user,
+            method,
+            password,
+            key,
+        };
+        let mut line = match serde_json::to_string(&attempt) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to encode honeypot record: {}", e);
+                return;
+            }
+        };
+        line.push('\n');
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("Failed to write honeypot log: {}", e);
+        }
+    }
+
+    fn take_token(&self) -> bool {
+        let mut limiter = self.limiter.lock().unwrap();
+        if !limiter.bucket.try_take(1) {
+            if limiter.dropped == 0 {
+                warn!(
+                    "Honeypot log rate exceeded {}/s, dropping attempts",
+                    limiter.bucket.rate()
+                );
+            }
+            limiter.dropped += 1;
+            return false;
+        }
+        if limiter.dropped > 0 {
+            info!(
+                "Honeypot log back under {}/s, dropped {} attempts",
+                limiter.bucket.rate(),
+                limiter.dropped
+            );
+            limiter.dropped = 0;
+        }
+        true
+    }
+}
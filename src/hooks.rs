@@ -0,0 +1,115 @@
+use crate::audit::unix_now;
+use crate::events::SessionInfo;
+use nix::sys::signal::{Signal, killpg};
+use nix::unistd::Pid;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+/// Programs run when a session's command starts and when the session ends,
+/// e.g. to call a webhook. They get the session as `TUIHOST_*` variables
+/// on top of the server's own env, run in the background and are killed
+/// after `timeout`; a failing hook is only logged.
+#[derive(Debug, Clone)]
+pub struct SessionHooks {
+    pub on_start: Option<PathBuf>,
+    pub on_end: Option<PathBuf>,
+    pub timeout: Duration,
+}
+
+impl SessionHooks {
+    pub(crate) fn session_started(&self, session: &SessionInfo, command: &str) {
+        if let Some(program) = &self.on_start {
+            let mut env = session_env(session);
+            env.push(("TUIHOST_COMMAND", command.to_string()));
+            self.spawn(program.clone(), "start", env);
+        }
+    }
+
+    pub(crate) fn session_ended(&self, session: &SessionInfo, reason: &str) {
+        if let Some(program) = &self.on_end {
+            let mut env = session_env(session);
+            env.push(("TUIHOST_ENDED_AT", unix_now().to_string()));
+            env.push(("TUIHOST_EXIT_REASON", reason.to_string()));
+            self.spawn(program.clone(), "end", env);
+        }
+    }
+
+    fn spawn(&self, program: PathBuf, event: &'static str, env: Vec<(&'static str, String)>) {
+        let timeout = self.timeout;
+        tokio::spawn(async move {
+            let spawned = Command::new(&program)
+                .env("TUIHOST_EVENT", event)
+                .envs(env)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                // Its own group, so a timeout also kills whatever it started
+                .process_group(0)
+                .kill_on_drop(true)
+                .spawn();
+            let mut child = match spawned {
+                Ok(child) => child,
+                Err(e) => {
+                    warn!(
+                        "Failed to run session {} hook {}: {}",
+                        event,
+                        program.display(),
+                        e
+                    );
+                    return;
+                }
+            };
+            let result = tokio::time::timeout(timeout, child.wait()).await;
+            if result.is_err()
+                && let Some(pid) = child.id()
+            {
+                let _ = killpg(Pid::from_raw(pid as i32), Signal::SIGKILL);
+            }
+            match result {
+                Ok(Ok(status)) if status.success() => {
+                    debug!("Session {} hook {} succeeded", event, program.display())
+                }
+                Ok(Ok(status)) => warn!(
+                    "Session {} hook {} exited with {}",
+                    event,
+                    program.display(),
+                    status
+                ),
+                Ok(Err(e)) => warn!(
+                    "Failed to wait for session {} hook {}: {}",
+                    event,
+                    program.display(),
+                    e
+                ),
+                Err(_) => warn!(
+                    "Session {} hook {} timed out after {:?}",
+                    event,
+                    program.display(),
+                    timeout
+                ),
+            }
+        });
+    }
+}
+
+fn session_env(session: &SessionInfo) -> Vec<(&'static str, String)> {
+    let started = session
+        .connected_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (ip, port) = session
+        .addr
+        .map(|addr| (addr.ip().to_string(), addr.port().to_string()))
+        .unwrap_or_default();
+    vec![
+        ("TUIHOST_SESSION_ID", session.id.to_string()),
+        ("TUIHOST_CLIENT_IP", ip),
+        ("TUIHOST_CLIENT_PORT", port),
+        ("TUIHOST_USER", session.user.clone().unwrap_or_default()),
+        ("TUIHOST_STARTED_AT", started.to_string()),
+    ]
+}
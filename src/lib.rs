@@ -0,0 +1,84 @@
+//! Host TUIs over SSH. No users. No passwords.
+//!
+//! The `tuihost` binary is a thin CLI over this crate. To embed the server
+//! in your own binary, build a [`TuiSshServer`] and run it with [`serve`]
+//! and a russh [`Config`](russh::server::Config):
+//!
+//! ```no_run
+//! use std::sync::{Arc, Mutex};
+//! use std::time::Duration;
+//! use tuihost::{Algorithms, CmdConfig, TuiSshServer, create_config, load_or_generate_host_keys};
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let server = TuiSshServer::builder()
+//!     .command(CmdConfig::builder().command("htop").arg("-d").arg("10").build()?)
+//!     .max_connections(100)
+//!     .session_duration(Duration::from_secs(3600))
+//!     .build()?;
+//!
+//! let host_keys = load_or_generate_host_keys(&["./host_key".into(), "./host_key_rsa".into()])?;
+//! let config = create_config(host_keys, 300, &Algorithms::modern());
+//! let listener = tokio::net::TcpListener::bind("0.0.0.0:2222").await?;
+//! let (shutdown, _) = tokio::sync::broadcast::channel(1);
+//! tuihost::serve(Arc::new(Mutex::new(server)), Arc::new(config), listener, shutdown).await;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Everything the CLI flags control lives in [`SessionConfig`],
+//! [`CommandMap`] and the [`TuiSshServerBuilder`] setters. Pass an
+//! [`Authenticator`] to [`TuiSshServerBuilder::authenticator`] to decide
+//! who may log in.
+
+mod access;
+mod algorithms;
+mod audit;
+mod auth;
+mod banner;
+mod broadcast;
+mod cgroup;
+mod events;
+mod geoip;
+mod handler;
+mod honeypot;
+mod hooks;
+mod pty;
+mod queue;
+mod rate;
+mod rdns;
+mod remote_allow;
+mod sandbox;
+mod serve;
+mod server;
+mod session_log;
+mod sessions;
+mod shared;
+#[cfg(test)]
+mod testing;
+mod totp;
+
+pub use access::{AcceptRateLimiter, AccessList, BanList, glob_match, parse_net};
+pub use algorithms::Algorithms;
+pub use audit::AuditLog;
+pub use auth::{AllowAllAuthenticator, AuthCommand, AuthFuture, Authenticator, FileAuthenticator};
+pub use banner::Banner;
+pub use broadcast::Broadcaster;
+pub use cgroup::{CgroupLimits, parse_cpus};
+pub use events::{EventObserver, SessionInfo};
+pub use geoip::{CountryFilter, parse_country};
+pub use handler::SessionHandler;
+pub use honeypot::HoneypotLog;
+pub use hooks::SessionHooks;
+pub use pty::{PtyReader, PtySession, PtyUnavailable, PtyWriter};
+pub use queue::ConnectionQueue;
+pub use remote_allow::RemoteAllowList;
+pub use sandbox::{RLimit, ResourceLimits, RunAs};
+pub use serve::serve;
+pub use server::{
+    CmdConfig, CmdConfigBuilder, CommandMap, DEFAULT_FULL_MESSAGE, DEFAULT_PTY_ERROR_MESSAGE,
+    PLACEHOLDERS, RespawnPolicy, SessionConfig, SingleSession, TuiSshServer, TuiSshServerBuilder,
+    create_config, generate_host_key, load_host_key, load_or_generate_host_key,
+    load_or_generate_host_keys,
+};
+pub use sessions::{ActiveSession, KillOutcome, SessionList};
+pub use totp::TotpSecrets;
@@ -0,0 +1,154 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::fmt::Write as _;
+use std::os::unix::net::UnixDatagram;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::layer::{Context as LayerContext, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum LogTarget {
+    /// Human-readable lines on stderr
+    Stderr,
+    /// One JSON object per line on stderr
+    Json,
+    /// The local syslog daemon
+    Syslog,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Facility {
+    User,
+    Daemon,
+    Auth,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl Facility {
+    // Facility codes from RFC 5424, section 6.2.1
+    fn code(self) -> u8 {
+        match self {
+            Facility::User => 1,
+            Facility::Daemon => 3,
+            Facility::Auth => 4,
+            Facility::Local0 => 16,
+            Facility::Local1 => 17,
+            Facility::Local2 => 18,
+            Facility::Local3 => 19,
+            Facility::Local4 => 20,
+            Facility::Local5 => 21,
+            Facility::Local6 => 22,
+            Facility::Local7 => 23,
+        }
+    }
+}
+
+/// Installs the global subscriber. `RUST_LOG` sets the levels; without it
+/// only tuihost's own events are logged, at info.
+pub fn init(target: LogTarget, facility: Facility, ansi: bool) -> Result<()> {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("tuihost=info"));
+    let registry = tracing_subscriber::registry().with(filter);
+    match target {
+        LogTarget::Stderr => registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .with_writer(std::io::stderr)
+                    .with_ansi(ansi),
+            )
+            .init(),
+        LogTarget::Json => registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_writer(std::io::stderr),
+            )
+            .init(),
+        LogTarget::Syslog => registry.with(SyslogLayer::connect(facility)?).init(),
+    }
+    Ok(())
+}
+
+/// Sends events to the local syslog daemon as RFC 3164 datagrams.
+struct SyslogLayer {
+    socket: UnixDatagram,
+    facility: Facility,
+}
+
+impl SyslogLayer {
+    fn connect(facility: Facility) -> Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket
+            .connect(SYSLOG_SOCKET)
+            .with_context(|| format!("Failed to connect to syslog at {}", SYSLOG_SOCKET))?;
+        Ok(Self { socket, facility })
+    }
+}
+
+// A span's fields, formatted once when it's created
+struct SpanFields(String);
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for SyslogLayer {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: LayerContext<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = String::new();
+        attrs.record(&mut MessageVisitor(&mut fields));
+        span.extensions_mut().insert(SpanFields(fields));
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: LayerContext<'_, S>) {
+        let severity = match *event.metadata().level() {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            Level::DEBUG | Level::TRACE => 7,
+        };
+        let mut line = format!(
+            "<{}>tuihost[{}]: {}: ",
+            self.facility.code() * 8 + severity,
+            std::process::id(),
+            event.metadata().target()
+        );
+        // Outermost first, like the stderr format: `channel{session=1 channel=0}: `
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                let _ = write!(line, "{}{{", span.name());
+                if let Some(fields) = span.extensions().get::<SpanFields>() {
+                    line.push_str(fields.0.trim_start());
+                }
+                line.push_str("}: ");
+            }
+        }
+        event.record(&mut MessageVisitor(&mut line));
+        // Nowhere left to report a failure to log
+        let _ = self.socket.send(line.as_bytes());
+    }
+}
+
+// Writes the message followed by any other fields as `name=value`
+struct MessageVisitor<'a>(&'a mut String);
+
+impl Visit for MessageVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, "{:?}", value);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+}
@@ -1,71 +1,593 @@
-mod handler;
-mod pty;
-mod server;
+mod control;
+mod daemon;
+mod health;
+mod logging;
 
 use anyhow::{Context, Result};
-use clap::Parser;
-use russh::keys::PrivateKey;
-use russh::server::Server as _;
-use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+use clap::{Parser, ValueEnum};
+use control::Control;
+use daemon::PidFile;
+use ipnet::IpNet;
+use logging::{Facility, LogTarget};
+use russh::server::Config;
+use russh::{MethodKind, MethodSet};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::net::TcpListener;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::broadcast;
 use tracing::{info, warn};
-use tracing_subscriber::EnvFilter;
 
-use crate::server::{CmdConfig, TuiSshServer, create_config, generate_host_key};
+use tuihost::{
+    AcceptRateLimiter, AccessList, Algorithms, AuditLog, AuthCommand, BanList, Banner,
+    CgroupLimits, CmdConfig, CommandMap, ConnectionQueue, CountryFilter, DEFAULT_FULL_MESSAGE,
+    DEFAULT_PTY_ERROR_MESSAGE, HoneypotLog, RLimit, RemoteAllowList, ResourceLimits, RespawnPolicy,
+    RunAs, SessionConfig, SessionHooks, TotpSecrets, TuiSshServer, create_config, load_host_key,
+    load_or_generate_host_keys, parse_country, parse_cpus, parse_net,
+};
+
+// Longest shutdown waits for sessions to close and their commands to exit
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
 
 #[derive(Parser, Debug)]
 #[command(name = "tuihost")]
 #[command(about = "SSH server that spawns a forced TUI application")]
 #[command(version)]
 struct Args {
-    /// Address to listen on
-    #[arg(short, long, default_value = "0.0.0.0:2222")]
-    listen: String,
+    /// Address to listen on (repeatable, e.g. an IPv4 and an IPv6 address)
+    #[arg(short, long, value_name = "ADDR", default_value = "0.0.0.0:2222")]
+    listen: Vec<String>,
 
-    /// Path to SSH host key (generated if missing)
-    #[arg(short = 'k', long, default_value = "./host_key")]
-    host_key: String,
+    /// Path to SSH host key, generated if missing (repeatable). Missing keys
+    /// whose file name contains "rsa" are generated as RSA-3072, others as Ed25519
+    #[arg(short = 'k', long, value_name = "PATH", default_value = "./host_key")]
+    host_key: Vec<String>,
 
-    /// Command to execute for each connection
-    #[arg(short, long)]
-    command: String,
+    /// Command to execute for each connection. Takes the same placeholders
+    /// as --args, e.g. /srv/menus/{user}
+    #[arg(short, long, required_unless_present_any = ["command_map", "app", "honeypot"])]
+    command: Option<String>,
+
+    /// Per-username command, e.g. `admin=/usr/bin/htop -d 10` (repeatable).
+    /// Users without an entry run --command, or are rejected if it's unset
+    #[arg(long, value_name = "USER=CMD")]
+    command_map: Vec<String>,
+
+    /// Command picked by logging in as `user+NAME`, e.g. `top=/usr/bin/htop`
+    /// (repeatable). Takes precedence over --command-map
+    #[arg(long, value_name = "NAME=CMD")]
+    app: Vec<String>,
 
-    /// Arguments to pass to the command
+    /// Separates the user from the app in login names like `alice+top`
+    #[arg(long, value_name = "SEP", default_value = "+")]
+    user_app_separator: String,
+
+    /// Arguments to pass to the command. {user}, {client_ip}, {client_port},
+    /// {session_id}, {cols}, {rows} and {term} expand per session
     #[arg(short, long, num_args = 0.., allow_hyphen_values = true)]
     args: Vec<String>,
 
-    /// Environment variables to pass to the command (KEY=VALUE)
+    /// Environment variables to pass to the command (KEY=VALUE). Values
+    /// expand the same placeholders as --args
     #[arg(short, long, value_name = "KEY=VALUE")]
     env: Vec<String>,
 
+    /// Variable for one user's command, added to or overriding --env
+    /// (repeatable). Values expand the same placeholders
+    #[arg(long, value_name = "USER:KEY=VALUE")]
+    user_env: Vec<String>,
+
+    /// Client-sent environment variable allowed to reach the command (repeatable)
+    #[arg(long, value_name = "NAME")]
+    allow_env: Vec<String>,
+
+    /// Glob (`*`, `?`) usernames must match to log in (repeatable; default: any)
+    #[arg(long, value_name = "GLOB")]
+    allow_user: Vec<String>,
+
+    /// User who may watch but not type; their input is discarded (repeatable)
+    #[arg(long, value_name = "NAME")]
+    readonly_user: Vec<String>,
+
+    /// Connections after the first for a username watch its command instead
+    /// of starting their own; their input is discarded
+    #[arg(long)]
+    shared: bool,
+
+    /// Working directory for the command ({user} expands to the SSH username)
+    #[arg(short, long)]
+    workdir: Option<String>,
+
+    /// Run the command as this user (and optionally group), with its HOME,
+    /// USER, LOGNAME and SHELL, starting in its home unless --workdir is set
+    #[arg(long, value_name = "USER[:GROUP]")]
+    run_as: Option<String>,
+
+    /// Max virtual memory of the command in bytes (RLIMIT_AS). The --limit-*
+    /// flags set soft and hard limits equal unless given as SOFT:HARD
+    #[arg(long, value_name = "BYTES", value_parser = RLimit::parse)]
+    limit_as: Option<RLimit>,
+
+    /// Max processes for the command's user (RLIMIT_NPROC)
+    #[arg(long, value_name = "N", value_parser = RLimit::parse)]
+    limit_nproc: Option<RLimit>,
+
+    /// Max CPU time of the command in seconds (RLIMIT_CPU)
+    #[arg(long, value_name = "SECS", value_parser = RLimit::parse)]
+    limit_cpu: Option<RLimit>,
+
+    /// Max open file descriptors of the command (RLIMIT_NOFILE)
+    #[arg(long, value_name = "N", value_parser = RLimit::parse)]
+    limit_nofile: Option<RLimit>,
+
+    /// Confine the command to this directory (requires root or
+    /// CAP_SYS_CHROOT). The command and --workdir are paths inside it
+    #[arg(long, value_name = "DIR")]
+    chroot: Option<PathBuf>,
+
+    /// cgroup v2 directory to create a cgroup per command run under, for
+    /// the --cgroup-* limits (e.g. /sys/fs/cgroup/tuihost)
+    #[arg(long, value_name = "DIR")]
+    cgroup_parent: Option<PathBuf>,
+
+    /// Hard memory limit of each command run in bytes (memory.max)
+    #[arg(long, value_name = "BYTES", requires = "cgroup_parent")]
+    cgroup_memory_max: Option<u64>,
+
+    /// CPUs each command run may use, e.g. 0.5 (cpu.max)
+    #[arg(long, value_name = "CPUS", requires = "cgroup_parent", value_parser = parse_cpus)]
+    cgroup_cpu_max: Option<f64>,
+
+    /// Algorithm preset; --kex, --ciphers and --macs override parts of it
+    #[arg(long, value_enum, default_value = "default")]
+    security_level: SecurityLevel,
+
+    /// Key exchange algorithms to offer, comma-separated in order of preference
+    #[arg(long, value_name = "LIST", value_delimiter = ',', value_parser = Algorithms::parse_kex)]
+    kex: Vec<russh::kex::Name>,
+
+    /// Ciphers to offer, comma-separated in order of preference
+    #[arg(long, value_name = "LIST", value_delimiter = ',', value_parser = Algorithms::parse_cipher)]
+    ciphers: Vec<russh::cipher::Name>,
+
+    /// MACs to offer, comma-separated in order of preference
+    #[arg(long, value_name = "LIST", value_delimiter = ',', value_parser = Algorithms::parse_mac)]
+    macs: Vec<russh::mac::Name>,
+
     /// Maximum concurrent connections (0 = unlimited)
     #[arg(long, default_value = "100")]
     max_connections: usize,
 
-    /// Session timeout in seconds (0 = no timeout)
+    /// Connections that may wait for a slot when --max-connections is
+    /// reached, instead of being refused at once (0 = no queue)
+    #[arg(long, value_name = "N", default_value = "0")]
+    queue_len: usize,
+
+    /// Seconds a queued connection waits for a slot before it's refused
+    #[arg(long, value_name = "SECS", default_value = "10")]
+    queue_timeout: u64,
+
+    /// What happens to input when the command isn't reading its stdin.
+    /// Block keeps keystrokes in order but stalls that connection for up to
+    /// --stdin-timeout; drop never stalls but loses input
+    #[arg(long, value_enum, default_value = "block")]
+    stdin_overflow: StdinOverflow,
+
+    /// Seconds input may wait for the command with --stdin-overflow block
+    #[arg(long, value_name = "SECS", default_value = "10")]
+    stdin_timeout: u64,
+
+    /// Line sent, before any handshake, to clients turned away by
+    /// --max-connections
+    #[arg(long, value_name = "TEXT", default_value = DEFAULT_FULL_MESSAGE)]
+    full_message: String,
+
+    /// Line shown before refusing a shell when no terminal can be
+    /// allocated, e.g. when the system is out of PTYs (empty = none)
+    #[arg(long, value_name = "TEXT", default_value = DEFAULT_PTY_ERROR_MESSAGE)]
+    pty_error_message: String,
+
+    /// Maximum new connections accepted per second (0 = unlimited)
+    #[arg(long, value_name = "N", default_value = "0")]
+    max_accept_rate: u32,
+
+    /// Maximum concurrent PTYs across all connections (0 = unlimited)
+    #[arg(long, default_value = "0")]
+    max_ptys: usize,
+
+    /// Maximum session channels open at once on one connection (0 = unlimited).
+    /// Each connection runs one command, so extra channels only waste resources
+    /// unless --multi-shell is set
+    #[arg(long, value_name = "N", default_value = "1")]
+    max_channels_per_connection: usize,
+
+    /// Allow a shell on each session channel of a connection instead of
+    /// disconnecting on the second one (raise --max-channels-per-connection too)
+    #[arg(long, conflicts_with = "shared")]
+    multi_shell: bool,
+
+    /// Close a session once its command has printed this many bytes (0 = unlimited)
+    #[arg(long, value_name = "N", default_value = "0")]
+    max_output_bytes: u64,
+
+    /// Cap on output bytes per second per session; the command is paused
+    /// while over it (0 = unlimited)
+    #[arg(
+        long,
+        visible_alias = "max-output-rate",
+        value_name = "N",
+        default_value = "0"
+    )]
+    max_output_bps: u64,
+
+    /// Hold command output for up to this many milliseconds so chatty
+    /// commands send fewer, larger packets (0 = send immediately)
+    #[arg(long, value_name = "MS", default_value = "0")]
+    output_flush_interval: u64,
+
+    /// Send held output once this many bytes pile up, without waiting for
+    /// --output-flush-interval
+    #[arg(long, value_name = "N", default_value = "16384")]
+    output_flush_bytes: usize,
+
+    /// Cap on input bytes per second per session; excess input is delayed,
+    /// and a channel that gets 256 KiB behind is closed (0 = unlimited)
+    #[arg(long, value_name = "N", default_value = "0")]
+    max_input_bps: u64,
+
+    /// Inactivity timeout in seconds (0 = no timeout)
     #[arg(long, default_value = "300")]
     timeout: u64,
 
-    /// Max session duration in seconds (0 = unlimited)
-    #[arg(long, default_value = "0")]
+    /// Seconds a connection gets to finish the handshake and authenticate
+    /// (0 = no limit)
+    #[arg(long, value_name = "SECS", default_value = "0")]
+    handshake_timeout: u64,
+
+    /// Disconnect connections this many seconds old, however active they
+    /// are (0 = no limit)
+    #[arg(long, value_name = "SECS", default_value = "0")]
+    max_connection_age: u64,
+
+    /// Auth methods clients may use: none, password, publickey,
+    /// keyboard-interactive. "none" lets anyone in without credentials unless
+    /// an authenticator refuses it; keyboard-interactive prompts for a password
+    #[arg(
+        long,
+        value_name = "LIST",
+        value_delimiter = ',',
+        default_value = "none,password,publickey",
+        value_parser = parse_auth_method
+    )]
+    auth_methods: Vec<MethodKind>,
+
+    /// Seconds every rejected auth attempt takes, so failures can't be told
+    /// apart by timing and brute force is slowed
+    #[arg(long, value_name = "SECS", default_value = "1")]
+    auth_rejection_time: u64,
+
+    /// Seconds for rejecting the first "none" attempt instead. Clients send
+    /// it to discover the allowed methods, so it shouldn't pay the penalty
+    #[arg(long, value_name = "SECS", default_value = "0")]
+    auth_rejection_time_initial: u64,
+
+    /// Max session duration in seconds regardless of activity (0 = unlimited)
+    #[arg(long, visible_alias = "max-session", default_value = "0")]
     max_session_duration: u64,
+
+    /// Minimum PTY width in columns
+    #[arg(long, default_value = "10")]
+    min_cols: u16,
+
+    /// Maximum PTY width in columns
+    #[arg(long, default_value = "500")]
+    max_cols: u16,
+
+    /// Minimum PTY height in rows
+    #[arg(long, default_value = "5")]
+    min_rows: u16,
+
+    /// Maximum PTY height in rows
+    #[arg(long, default_value = "200")]
+    max_rows: u16,
+
+    /// Reject terminals smaller than the minimum size instead of clamping
+    #[arg(long)]
+    reject_small: bool,
+
+    /// Clear the client's screen before the MOTD and command
+    #[arg(long)]
+    clear_on_start: bool,
+
+    /// Switch the client to the alternate screen for the session and back
+    /// when it ends, leaving their scrollback untouched
+    #[arg(long)]
+    alt_screen: bool,
+
+    /// TERM to give the command, instead of the client's terminal type
+    #[arg(long, value_name = "TERM")]
+    force_term: Option<String>,
+
+    /// Deliver signals clients send (e.g. INT, TERM) to the command. Off by
+    /// default so clients can't kill it; resizes never come this way
+    #[arg(long)]
+    forward_signals: bool,
+
+    /// Look up each client's hostname, show it in logs and pass it to the
+    /// command as TUIHOST_CLIENT_HOST. Bounded by a short timeout and
+    /// cached per address for a few minutes; unset on failure
+    #[arg(long)]
+    reverse_dns: bool,
+
+    /// Drop clients whose address doesn't reverse-resolve to a hostname
+    /// (implies --reverse-dns)
+    #[arg(long)]
+    require_reverse_dns: bool,
+
+    /// Refuse shell requests from clients that didn't request a PTY
+    /// (e.g. `ssh -T`)
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set)]
+    require_pty: bool,
+
+    /// Send SIGWINCH to the command every N seconds to force a redraw (0 = disabled)
+    #[arg(long, value_name = "SECS", default_value = "0")]
+    refresh_interval: u64,
+
+    /// Deny connections from this CIDR range (repeatable)
+    #[arg(long, value_name = "CIDR", value_parser = parse_net)]
+    deny: Vec<IpNet>,
+
+    /// Only allow connections from these CIDR ranges (repeatable)
+    #[arg(long, value_name = "CIDR", value_parser = parse_net)]
+    allow: Vec<IpNet>,
+
+    /// Only allow connections from the CIDRs listed at this http:// URL, one
+    /// per line. Startup fails if it can't be fetched
+    #[arg(long, value_name = "URL")]
+    allow_url: Option<String>,
+
+    /// Seconds between refreshes of --allow-url; failed refreshes keep the
+    /// last list
+    #[arg(long, value_name = "SECS", default_value = "300")]
+    allow_refresh: u64,
+
+    /// MaxMind country database (GeoLite2-Country.mmdb) for --allow-country
+    /// and --deny-country
+    #[arg(long, value_name = "PATH")]
+    geoip_db: Option<PathBuf>,
+
+    /// Only allow connections from this country, e.g. DE (repeatable)
+    #[arg(long, value_name = "CODE", value_parser = parse_country, requires = "geoip_db")]
+    allow_country: Vec<String>,
+
+    /// Deny connections from this country (repeatable)
+    #[arg(long, value_name = "CODE", value_parser = parse_country, requires = "geoip_db")]
+    deny_country: Vec<String>,
+
+    /// What to do with addresses the database has no country for, such as
+    /// private ranges
+    #[arg(long, value_name = "POLICY", value_enum, default_value = "allow")]
+    geoip_unknown: UnknownCountry,
+
+    /// Program deciding password/publickey auth; called as `<prog> password <user>`
+    /// (password on stdin) or `<prog> publickey <user> <fingerprint>`, exit 0 accepts
+    #[arg(long, value_name = "PATH")]
+    auth_command: Option<PathBuf>,
+
+    /// Seconds to wait for --auth-command before rejecting
+    #[arg(long, value_name = "SECS", default_value = "5")]
+    auth_command_timeout: u64,
+
+    /// Program run in the background when a session's command starts, with
+    /// the session in TUIHOST_* env vars (SESSION_ID, CLIENT_IP, USER, ...)
+    #[arg(long, value_name = "PATH")]
+    on_session_start: Option<PathBuf>,
+
+    /// Program run in the background when a started session ends; also gets
+    /// TUIHOST_ENDED_AT and TUIHOST_EXIT_REASON
+    #[arg(long, value_name = "PATH")]
+    on_session_end: Option<PathBuf>,
+
+    /// Seconds a session hook may run before it's killed
+    #[arg(long, value_name = "SECS", default_value = "10")]
+    hook_timeout: u64,
+
+    /// Auth failures within --ban-window that trigger a ban (0 = disabled)
+    #[arg(long, default_value = "0")]
+    ban_threshold: usize,
+
+    /// Window in seconds for counting auth failures
+    #[arg(long, default_value = "60")]
+    ban_window: u64,
+
+    /// Ban duration in seconds
+    #[arg(long, default_value = "600")]
+    ban_duration: u64,
+
+    /// File shown to clients before authentication (reloaded on SIGHUP)
+    #[arg(long, value_name = "PATH")]
+    banner: Option<String>,
+
+    /// File shown in the terminal before the command starts ({user} and {addr}
+    /// are substituted; reloaded on SIGHUP)
+    #[arg(long, value_name = "PATH")]
+    motd: Option<String>,
+
+    /// Seconds to keep the MOTD on screen before showing the command's output
+    #[arg(long, value_name = "SECS", default_value = "0", requires = "motd")]
+    motd_delay: u64,
+
+    /// Command line run in the session before the main command, e.g. to
+    /// print a status summary. The main command starts once it exits
+    #[arg(long, value_name = "CMD")]
+    welcome_command: Option<String>,
+
+    /// Keys typed into the command once it starts, e.g. to open a specific
+    /// view. Understands \n, \r, \t, \e, \\ and \xNN
+    #[arg(long, value_name = "STRING")]
+    initial_input: Option<String>,
+
+    /// Milliseconds to let the command set up its terminal before typing
+    /// --initial-input; slow starters may need more
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value = "200",
+        requires = "initial_input"
+    )]
+    initial_input_delay: u64,
+
+    /// Re-run the command when it exits instead of closing the session
+    #[arg(long)]
+    respawn: bool,
+
+    /// Max respawns within --respawn-window before the session is closed
+    #[arg(long, value_name = "N", default_value = "5", requires = "respawn")]
+    respawn_max: usize,
+
+    /// Window in seconds for counting respawns
+    #[arg(long, value_name = "SECS", default_value = "60", requires = "respawn")]
+    respawn_window: u64,
+
+    /// Validate the configuration, print a summary and exit without serving,
+    /// creating files or touching cgroups
+    #[arg(long)]
+    check: bool,
+
+    /// Serve a single shell session: refuse new connections once it starts
+    /// and exit when it ends
+    #[arg(long)]
+    once: bool,
+
+    /// Write the server's pid to this file, removing it on shutdown
+    #[arg(long, value_name = "PATH")]
+    pid_file: Option<PathBuf>,
+
+    /// Detach from the terminal and run in the background
+    #[arg(long)]
+    daemon: bool,
+
+    /// Where logs go
+    #[arg(long, value_enum, default_value = "stderr")]
+    log_target: LogTarget,
+
+    /// Syslog facility for --log-target syslog
+    #[arg(long, value_enum, default_value = "daemon")]
+    syslog_facility: Facility,
+
+    /// File to append logs to when running with --daemon (default: discarded)
+    #[arg(long, value_name = "PATH", requires = "daemon")]
+    log_file: Option<PathBuf>,
+
+    /// File whose first line is shown on every live terminal on SIGUSR2
+    #[arg(long, value_name = "PATH")]
+    broadcast_file: Option<PathBuf>,
+
+    /// Append one JSON line per connection to this file
+    #[arg(long, value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+
+    /// Directory for per-session logs of auth, terminal size, spawn and exit
+    /// events, one file per connection
+    #[arg(long, value_name = "DIR")]
+    session_log_dir: Option<PathBuf>,
+
+    /// FOR DEVELOPMENT ONLY: log a hex/ASCII dump of all terminal I/O at
+    /// trace level (RUST_LOG=tuihost=trace). This logs everything users
+    /// type, passwords included
+    #[arg(long)]
+    debug_io: bool,
+
+    /// Bytes of each read or write shown by --debug-io
+    #[arg(long, value_name = "N", default_value = "256")]
+    debug_io_bytes: usize,
+
+    /// File of `user:SECRET` lines (base32 TOTP secrets). Those users must
+    /// also enter a code from their authenticator app after logging in
+    #[arg(long, value_name = "PATH")]
+    totp_file: Option<PathBuf>,
+
+    /// Let users without a TOTP secret in with one factor (default: refuse them)
+    #[arg(long, requires = "totp_file")]
+    totp_exempt_missing: bool,
+
+    /// Honeypot mode: refuse every login and append the usernames,
+    /// passwords and keys tried to this file as JSON lines
+    #[arg(long, value_name = "PATH")]
+    honeypot: Option<PathBuf>,
+
+    /// Max attempts written to the honeypot log per second; the rest are dropped
+    #[arg(long, value_name = "N", default_value = "20", requires = "honeypot")]
+    honeypot_rate: u32,
+
+    /// Address for the HTTP health endpoint (/healthz, /readyz)
+    #[arg(long, value_name = "ADDR")]
+    health_listen: Option<String>,
+
+    /// Unix socket (mode 0600) for admin commands, one per line: list,
+    /// kill <session_id>, broadcast <message>, drain on|off, reload
+    #[arg(long, value_name = "PATH")]
+    control_socket: Option<PathBuf>,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env().add_directive("tuihost=info".parse()?))
-        .init();
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum StdinOverflow {
+    /// Wait up to --stdin-timeout for the command to read, then drop
+    Block,
+    /// Drop input the command's terminal can't take right away
+    Drop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum UnknownCountry {
+    Allow,
+    Deny,
+}
 
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum SecurityLevel {
+    /// russh's defaults
+    Default,
+    /// AEAD ciphers, ETM MACs, curve25519/ML-KEM and SHA-2 host key signatures only
+    Modern,
+}
+
+fn main() -> Result<()> {
     let args = Args::parse();
 
-    info!(
-        "Starting tuihost server on {} with command: {} {:?}",
-        args.listen, args.command, args.args
-    );
+    logging::init(args.log_target, args.syslog_facility, !args.daemon)?;
+
+    // A check must work next to the running server it's vetting
+    if let Some(pid_file) = &args.pid_file
+        && !args.check
+    {
+        PidFile::check(pid_file)?;
+    }
+    if args.daemon && !args.check {
+        daemon::daemonize(args.log_file.as_deref())?;
+    }
 
-    let host_key = load_or_generate_host_key(&args.host_key)?;
+    tokio::runtime::Runtime::new()
+        .context("Failed to start tokio runtime")?
+        .block_on(run(args))
+}
+
+async fn run(args: Args) -> Result<()> {
+    match &args.command {
+        Some(command) => info!(
+            "Starting tuihost server on {} with command: {} {:?}",
+            args.listen.join(", "),
+            command,
+            args.args
+        ),
+        None => info!(
+            "Starting tuihost server on {} with per-user commands only",
+            args.listen.join(", ")
+        ),
+    }
 
     let env_vars: Vec<(String, String)> = args
         .env
@@ -82,62 +604,745 @@ async fn main() -> Result<()> {
         })
         .collect();
 
-    let tui_config = CmdConfig {
-        command: args.command,
-        args: args.args,
+    let run_as = args.run_as.as_deref().map(RunAs::resolve).transpose()?;
+    if let Some(run_as) = &run_as {
+        info!(
+            "Commands will run as {} (uid {}, gid {})",
+            run_as.user, run_as.uid, run_as.gid
+        );
+        if run_as.home.is_none() {
+            warn!(
+                "{} has no home directory; HOME won't be set and commands start in /",
+                run_as.user
+            );
+        }
+    }
+
+    let limits = ResourceLimits {
+        address_space: args.limit_as,
+        nproc: args.limit_nproc,
+        cpu_secs: args.limit_cpu,
+        nofile: args.limit_nofile,
+    };
+    limits.check().context("Invalid resource limit")?;
+
+    // Without write access to the cgroup fs, commands still run, just
+    // without these limits. A check only looks.
+    let cgroup = args.cgroup_parent.and_then(|parent| {
+        let cgroup = CgroupLimits {
+            parent,
+            memory_max: args.cgroup_memory_max,
+            cpu_max: args.cgroup_cpu_max,
+        };
+        let ready = if args.check {
+            cgroup.check()
+        } else {
+            cgroup.prepare()
+        };
+        match ready {
+            Ok(()) => {
+                info!(
+                    "Commands will run in cgroups under {}",
+                    cgroup.parent.display()
+                );
+                Some(cgroup)
+            }
+            Err(e) => {
+                warn!("{:#}; running commands without cgroup limits", e);
+                None
+            }
+        }
+    });
+
+    if args.debug_io {
+        warn!(
+            "--debug-io is on: ALL terminal I/O, including typed passwords, is logged at trace level"
+        );
+        if !tracing::enabled!(tracing::Level::TRACE) {
+            warn!(
+                "--debug-io has no effect unless trace logging is enabled, e.g. RUST_LOG=tuihost=trace"
+            );
+        }
+    }
+
+    if let Some(root) = &args.chroot {
+        anyhow::ensure!(
+            root.is_dir(),
+            "Chroot directory does not exist: {}",
+            root.display()
+        );
+        if !nix::unistd::Uid::effective().is_root() {
+            warn!("Not running as root; --chroot fails at spawn without CAP_SYS_CHROOT");
+        }
+    }
+
+    // Per-user directories can only be checked once the user is known
+    if let Some(workdir) = &args.workdir
+        && !workdir.contains("{user}")
+    {
+        let host_dir = match &args.chroot {
+            Some(root) => root.join(workdir.trim_start_matches('/')),
+            None => PathBuf::from(workdir),
+        };
+        std::fs::read_dir(&host_dir)
+            .with_context(|| format!("Working directory {} is not accessible", workdir))?;
+    }
+
+    let base_config = CmdConfig {
+        command: String::new(),
+        args: Vec::new(),
         env: env_vars,
+        workdir: args.workdir,
+        run_as,
+        limits,
+        chroot: args.chroot,
+        cgroup,
+    };
+
+    let mut users = HashMap::new();
+    for entry in &args.command_map {
+        let (user, command_line) = entry
+            .split_once('=')
+            .with_context(|| format!("Invalid command map entry (expected USER=CMD): {}", entry))?;
+        let mut parts = command_line.split_whitespace().map(str::to_string);
+        let command = parts
+            .next()
+            .with_context(|| format!("Empty command for user {}", user))?;
+        info!("Command for user {}: {}", user, command_line);
+        users.insert(
+            user.to_string(),
+            CmdConfig {
+                command,
+                args: parts.collect(),
+                ..base_config.clone()
+            },
+        );
+    }
+
+    let mut apps = HashMap::new();
+    for entry in &args.app {
+        let (app, command_line) = entry
+            .split_once('=')
+            .with_context(|| format!("Invalid --app (expected NAME=CMD): {}", entry))?;
+        let mut parts = command_line.split_whitespace().map(str::to_string);
+        let command = parts
+            .next()
+            .with_context(|| format!("Empty command for app {}", app))?;
+        info!("Command for app {}: {}", app, command_line);
+        apps.insert(
+            app.to_string(),
+            CmdConfig {
+                command,
+                args: parts.collect(),
+                ..base_config.clone()
+            },
+        );
+    }
+
+    // Takes the env, workdir and limits of whichever command it precedes
+    let welcome_command = match &args.welcome_command {
+        Some(command_line) => {
+            let mut parts = command_line.split_whitespace().map(str::to_string);
+            let command = parts.next().context("Empty --welcome-command")?;
+            let config = CmdConfig {
+                command,
+                args: parts.collect(),
+                ..base_config.clone()
+            };
+            config.resolve().context("Invalid --welcome-command")?;
+            Some(config)
+        }
+        None => None,
+    };
+
+    let mut user_env: HashMap<String, Vec<(String, String)>> = HashMap::new();
+    for entry in &args.user_env {
+        let parsed = entry.split_once(':').and_then(|(user, var)| {
+            let (key, value) = var.split_once('=')?;
+            (!user.is_empty() && !key.is_empty()).then_some((user, key, value))
+        });
+        let (user, key, value) = parsed
+            .with_context(|| format!("Invalid --user-env (expected USER:KEY=VALUE): {}", entry))?;
+        let vars = user_env.entry(user.to_string()).or_default();
+        vars.retain(|(existing, _)| existing != key);
+        vars.push((key.to_string(), value.to_string()));
+    }
+
+    let commands = CommandMap {
+        default: args.command.map(|command| CmdConfig {
+            command,
+            args: args.args,
+            ..base_config
+        }),
+        users,
+        user_env,
+        apps,
+    };
+    // Fail before binding rather than on every connection
+    commands.validate()?;
+
+    let host_keys = if args.check {
+        // Missing keys are generated on a real start, not by a check
+        args.host_key
+            .iter()
+            .filter(|path| Path::new(path).exists())
+            .map(|path| load_host_key(path))
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        load_or_generate_host_keys(&args.host_key)?
     };
 
-    let ssh_config = create_config(host_key, args.timeout);
+    anyhow::ensure!(
+        args.min_cols <= args.max_cols,
+        "--min-cols ({}) must not exceed --max-cols ({})",
+        args.min_cols,
+        args.max_cols
+    );
+    anyhow::ensure!(
+        args.min_rows <= args.max_rows,
+        "--min-rows ({}) must not exceed --max-rows ({})",
+        args.min_rows,
+        args.max_rows
+    );
+
+    let banner = args
+        .banner
+        .as_deref()
+        .map(Banner::load)
+        .transpose()?
+        .map(Arc::new);
+
+    let motd = args
+        .motd
+        .as_deref()
+        .map(Banner::load)
+        .transpose()?
+        .map(Arc::new);
+
+    let mut session_config = SessionConfig {
+        min_cols: args.min_cols,
+        max_cols: args.max_cols,
+        min_rows: args.min_rows,
+        max_rows: args.max_rows,
+        reject_small: args.reject_small,
+        clear_on_start: args.clear_on_start,
+        alt_screen: args.alt_screen,
+        force_term: args.force_term,
+        forward_signals: args.forward_signals,
+        reverse_dns: args.reverse_dns || args.require_reverse_dns,
+        require_reverse_dns: args.require_reverse_dns,
+        require_pty: args.require_pty,
+        allow_env: args.allow_env,
+        readonly_users: args.readonly_user,
+        allowed_users: args.allow_user,
+        shared_sessions: args.shared,
+        multi_shell: args.multi_shell,
+        refresh_interval: (args.refresh_interval > 0)
+            .then(|| Duration::from_secs(args.refresh_interval)),
+        banner: banner.clone(),
+        motd: motd.clone(),
+        motd_delay: (args.motd_delay > 0).then(|| Duration::from_secs(args.motd_delay)),
+        max_ptys: args.max_ptys,
+        max_channels: args.max_channels_per_connection,
+        max_output_bytes: args.max_output_bytes,
+        max_output_bps: args.max_output_bps,
+        output_flush_interval: Duration::from_millis(args.output_flush_interval),
+        output_flush_bytes: args.output_flush_bytes,
+        max_input_bps: args.max_input_bps,
+        full_message: args.full_message,
+        pty_error_message: args.pty_error_message,
+        stdin_timeout: match args.stdin_overflow {
+            StdinOverflow::Block => Duration::from_secs(args.stdin_timeout),
+            StdinOverflow::Drop => Duration::ZERO,
+        },
+        welcome_command,
+        initial_input: args
+            .initial_input
+            .as_deref()
+            .map(unescape)
+            .transpose()
+            .context("Invalid --initial-input")?,
+        initial_input_delay: Duration::from_millis(args.initial_input_delay),
+        respawn: args.respawn.then(|| RespawnPolicy {
+            max: args.respawn_max,
+            window: Duration::from_secs(args.respawn_window),
+        }),
+        // Opened once the check is past, as opening creates them
+        audit_log: None,
+        session_log_dir: args.session_log_dir.clone(),
+        debug_io: args.debug_io.then_some(args.debug_io_bytes),
+        auth_methods: MethodSet::from(args.auth_methods.as_slice()),
+        totp: match &args.totp_file {
+            Some(path) => {
+                let mut totp = TotpSecrets::load(path)?;
+                totp.exempt_missing = args.totp_exempt_missing;
+                Some(Arc::new(totp))
+            }
+            None => None,
+        },
+        honeypot: None,
+        single_session: args.once.then(Arc::default),
+        app_separator: args.user_app_separator,
+        hooks: (args.on_session_start.is_some() || args.on_session_end.is_some()).then(|| {
+            Arc::new(SessionHooks {
+                on_start: args.on_session_start,
+                on_end: args.on_session_end,
+                timeout: Duration::from_secs(args.hook_timeout),
+            })
+        }),
+    };
+    let single_session = session_config.single_session.clone();
+
+    let access_list = AccessList {
+        allow: args.allow,
+        deny: args.deny,
+    };
+    if !access_list.is_empty() {
+        info!(
+            "IP access list: {} allow, {} deny rules",
+            access_list.allow.len(),
+            access_list.deny.len()
+        );
+    }
+
+    let country_filter = match &args.geoip_db {
+        Some(path) => {
+            let mut filter = CountryFilter::open(path)?;
+            filter.allow = args.allow_country;
+            filter.deny = args.deny_country;
+            filter.allow_unknown = args.geoip_unknown == UnknownCountry::Allow;
+            info!(
+                "Country filter: {} allow, {} deny, unknown {}",
+                filter.allow.len(),
+                filter.deny.len(),
+                if filter.allow_unknown {
+                    "allowed"
+                } else {
+                    "denied"
+                }
+            );
+            Some(filter)
+        }
+        None => None,
+    };
+
+    let ban_list = if args.ban_threshold > 0 {
+        Some(BanList::new(
+            args.ban_threshold,
+            Duration::from_secs(args.ban_window),
+            Duration::from_secs(args.ban_duration),
+        ))
+    } else {
+        None
+    };
+
+    let mut algorithms = match args.security_level {
+        SecurityLevel::Default => Algorithms::default(),
+        SecurityLevel::Modern => Algorithms::modern(),
+    };
+    if !args.kex.is_empty() {
+        algorithms.kex = Some(args.kex);
+    }
+    if !args.ciphers.is_empty() {
+        algorithms.ciphers = Some(args.ciphers);
+    }
+    if !args.macs.is_empty() {
+        algorithms.macs = Some(args.macs);
+    }
+
+    let mut ssh_config = create_config(host_keys, args.timeout, &algorithms);
+    // Advertised from the start, so clients don't try methods that are off
+    ssh_config.methods = session_config.auth_methods.clone();
+    if args.honeypot.is_some()
+        && !ssh_config
+            .methods
+            .contains(&MethodKind::KeyboardInteractive)
+    {
+        ssh_config.methods.push(MethodKind::KeyboardInteractive);
+    }
+    ssh_config.auth_rejection_time = Duration::from_secs(args.auth_rejection_time);
+    ssh_config.auth_rejection_time_initial =
+        Some(Duration::from_secs(args.auth_rejection_time_initial));
+    let handshake_timeout =
+        (args.handshake_timeout > 0).then(|| Duration::from_secs(args.handshake_timeout));
+    let max_connection_age =
+        (args.max_connection_age > 0).then(|| Duration::from_secs(args.max_connection_age));
     let max_session_duration = if args.max_session_duration > 0 {
-        Some(std::time::Duration::from_secs(args.max_session_duration))
+        Some(Duration::from_secs(args.max_session_duration))
     } else {
         None
     };
 
-    let mut server = TuiSshServer::new(tui_config, args.max_connections, max_session_duration);
+    if args.check {
+        // Only looked at: a real start creates the directory and files
+        if let Some(dir) = &args.session_log_dir {
+            anyhow::ensure!(
+                !dir.exists() || dir.is_dir(),
+                "Session log directory {} is not a directory",
+                dir.display()
+            );
+            check_writable(dir).context("Invalid --session-log-dir")?;
+        }
+        if let Some(path) = &args.audit_log {
+            check_writable(path).context("Invalid --audit-log")?;
+        }
+        if let Some(path) = &args.honeypot {
+            check_writable(path).context("Invalid --honeypot")?;
+        }
+        if let Some(url) = &args.allow_url {
+            let list = RemoteAllowList::load(url)
+                .await
+                .context("Failed to load --allow-url")?;
+            info!("Remote allow list: {} entries from {}", list.len(), url);
+        }
+        return check_summary(
+            &args.listen,
+            &args.host_key,
+            &ssh_config,
+            &commands,
+            args.auth_command.as_deref(),
+        )
+        .await;
+    }
+
+    if let Some(dir) = &args.session_log_dir {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create session log directory {}", dir.display()))?;
+    }
+    session_config.audit_log = args
+        .audit_log
+        .as_deref()
+        .map(AuditLog::open)
+        .transpose()?
+        .map(Arc::new);
+    session_config.honeypot = args
+        .honeypot
+        .as_deref()
+        .map(|path| HoneypotLog::open(path, args.honeypot_rate))
+        .transpose()?
+        .map(Arc::new);
+
+    let mut builder = TuiSshServer::builder()
+        .commands(commands)
+        .session_config(session_config)
+        .access_list(access_list)
+        .max_connections(args.max_connections);
+    if let Some(ban_list) = ban_list {
+        builder = builder.ban_list(ban_list);
+    }
+    if args.max_accept_rate > 0 {
+        builder = builder.accept_rate(AcceptRateLimiter::new(args.max_accept_rate));
+    }
+    if let Some(duration) = max_session_duration {
+        builder = builder.session_duration(duration);
+    }
+    if args.queue_len > 0 {
+        builder = builder.connection_queue(Arc::new(ConnectionQueue::new(
+            args.queue_len,
+            Duration::from_secs(args.queue_timeout),
+        )));
+    }
+    if let Some(timeout) = handshake_timeout {
+        builder = builder.handshake_timeout(timeout);
+    }
+    if let Some(age) = max_connection_age {
+        builder = builder.max_connection_age(age);
+    }
+    if let Some(program) = args.auth_command {
+        builder = builder.authenticator(AuthCommand {
+            program,
+            timeout: Duration::from_secs(args.auth_command_timeout),
+        });
+    }
+    if let Some(filter) = country_filter {
+        builder = builder.country_filter(filter);
+    }
+    if let Some(url) = &args.allow_url {
+        let list = Arc::new(
+            RemoteAllowList::load(url)
+                .await
+                .context("Failed to load --allow-url")?,
+        );
+        info!("Remote allow list: {} entries from {}", list.len(), url);
+        let refreshed = list.clone();
+        let interval = Duration::from_secs(args.allow_refresh.max(1));
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                refreshed.refresh().await;
+            }
+        });
+        builder = builder.remote_allow_list(list);
+    }
+    let server = builder.build()?;
+
+    // Bind everything up front so a bad address fails startup
+    let mut listeners = Vec::with_capacity(args.listen.len());
+    for listen in &args.listen {
+        let listener = TcpListener::bind(listen)
+            .await
+            .with_context(|| format!("Failed to bind to {}", listen))?;
+        info!("SSH server listening on {}", listen);
+        listeners.push(listener);
+    }
+
+    // Removed again when `run` returns
+    let _pid_file = args.pid_file.as_deref().map(PidFile::create).transpose()?;
+
+    let draining = server.draining();
+    if let Some(health_listen) = &args.health_listen {
+        health::serve(health_listen, draining.clone()).await?;
+    }
+
+    // Removed again when `run` returns
+    let _control_socket = match &args.control_socket {
+        Some(path) => {
+            let (banner, motd) = (banner.clone(), motd.clone());
+            let control = Control {
+                sessions: server.session_list(),
+                broadcaster: server.broadcaster(),
+                draining: draining.clone(),
+                reload: Box::new(move || reload(banner.as_deref(), motd.as_deref())),
+            };
+            Some(control::serve(path, control)?)
+        }
+        None => None,
+    };
+
+    tokio::spawn(async move {
+        let mut sighup = signal(SignalKind::hangup()).expect("Failed to install SIGHUP handler");
+        while sighup.recv().await.is_some() {
+            reload(banner.as_deref(), motd.as_deref());
+        }
+    });
 
-    let listener = TcpListener::bind(&args.listen)
-        .await
-        .with_context(|| format!("Failed to bind to {}", args.listen))?;
+    if let Some(path) = args.broadcast_file.clone() {
+        let broadcaster = server.broadcaster();
+        tokio::spawn(async move {
+            let mut sigusr2 =
+                signal(SignalKind::user_defined2()).expect("Failed to install SIGUSR2 handler");
+            while sigusr2.recv().await.is_some() {
+                match std::fs::read_to_string(&path) {
+                    Ok(message) => {
+                        let sent = broadcaster.send(&message).await;
+                        info!("Broadcast sent to {} sessions", sent);
+                    }
+                    Err(e) => warn!("Failed to read {}: {}", path.display(), e),
+                }
+            }
+        });
+    }
 
-    info!("SSH server listening on {}", args.listen);
+    let (shutdown_tx, _) = broadcast::channel(1);
+    let shutdown = shutdown_tx.clone();
+    let stop_accepting = draining.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutdown requested, disconnecting clients");
+        stop_accepting.store(true, Ordering::SeqCst);
+        let _ = shutdown.send("server shutting down".to_string());
+    });
 
-    server
-        .run_on_socket(std::sync::Arc::new(ssh_config), &listener)
-        .await?;
+    if let Some(single) = single_session {
+        let shutdown = shutdown_tx.clone();
+        tokio::spawn(async move {
+            single.started().await;
+            info!("Session started, refusing new connections (--once)");
+            draining.store(true, Ordering::SeqCst);
+            single.ended().await;
+            info!("Session ended, exiting (--once)");
+            let _ = shutdown.send("server shutting down".to_string());
+        });
+    }
+
+    // One accept loop per listener, all sharing the server's limits and counters
+    let server = Arc::new(Mutex::new(server));
+    let ssh_config = Arc::new(ssh_config);
+    let loops: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| {
+            tokio::spawn(tuihost::serve(
+                server.clone(),
+                ssh_config.clone(),
+                listener,
+                shutdown_tx.clone(),
+            ))
+        })
+        .collect();
+    for accept in loops {
+        let _ = accept.await;
+    }
+    // Closing sessions give their commands a grace period to exit; leaving
+    // now would kill them outright along with the runtime
+    let idle = server.lock().unwrap().idle();
+    if tokio::time::timeout(SHUTDOWN_GRACE, idle).await.is_err() {
+        warn!(
+            "Sessions still open after {}s, exiting anyway",
+            SHUTDOWN_GRACE.as_secs()
+        );
+    }
+    info!("Server stopped");
 
     Ok(())
 }
 
-fn load_or_generate_host_key(path: &str) -> Result<PrivateKey> {
-    let key_path = Path::new(path);
+// Finishes the checks that need more than parsing and prints what would run
+async fn check_summary(
+    listen: &[String],
+    host_key_paths: &[String],
+    ssh_config: &Config,
+    commands: &CommandMap,
+    auth_command: Option<&Path>,
+) -> Result<()> {
+    let mut addrs = Vec::new();
+    for listen in listen {
+        addrs.extend(
+            tokio::net::lookup_host(listen)
+                .await
+                .with_context(|| format!("Invalid listen address {}", listen))?,
+        );
+    }
+    if let Some(program) = auth_command {
+        CmdConfig::new(program.to_string_lossy())
+            .resolve()
+            .context("Invalid --auth-command")?;
+    }
 
-    if key_path.exists() {
-        info!("Loading host key from: {}", path);
-        let key_data = std::fs::read_to_string(key_path).context("Failed to read host key file")?;
-        let key = key_data
-            .parse::<PrivateKey>()
-            .map_err(|e| anyhow::anyhow!("Failed to parse host key: {}", e))?;
-        Ok(key)
-    } else {
-        warn!(
-            "Host key not found, generating new Ed25519 key at: {}",
-            path
+    println!("Configuration OK");
+    println!(
+        "  listen: {}",
+        addrs
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let mut loaded = ssh_config.keys.iter();
+    for path in host_key_paths {
+        if Path::new(path).exists() {
+            let key = loaded.next().expect("one key per existing path");
+            println!("  host key: {} ({})", path, key.algorithm());
+        } else {
+            println!("  host key: {} (missing, generated on start)", path);
+        }
+    }
+    if let Some(config) = &commands.default {
+        println!(
+            "  command: {} {:?}",
+            config.resolve()?.display(),
+            config.args
         );
-        let key = generate_host_key();
+    }
+    let mut users: Vec<_> = commands.users.iter().collect();
+    users.sort_by_key(|(user, _)| user.as_str());
+    for (user, config) in users {
+        println!(
+            "  command for {}: {} {:?}",
+            user,
+            config.resolve()?.display(),
+            config.args
+        );
+    }
+    if let Some(program) = auth_command {
+        println!("  auth command: {}", program.display());
+    }
+    Ok(())
+}
 
-        let openssh_key = key
-            .to_openssh(ssh_key::LineEnding::LF)
-            .map_err(|e| anyhow::anyhow!("Failed to encode key: {}", e))?;
-        std::fs::write(key_path, openssh_key.as_bytes())
-            .context("Failed to write host key file")?;
+// Whether a file or directory could be created, or written if it exists, at
+// `path`, found without creating or opening anything
+fn check_writable(path: &Path) -> Result<()> {
+    let mut existing = path;
+    while !existing.exists() {
+        existing = match existing.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+    }
+    nix::unistd::access(existing, nix::unistd::AccessFlags::W_OK)
+        .with_context(|| format!("{} is not writable", existing.display()))
+}
+
+// Decodes the backslash escapes accepted by --initial-input
+fn unescape(text: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('t') => bytes.push(b'\t'),
+            Some('e') => bytes.push(0x1b),
+            Some('\\') => bytes.push(b'\\'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let byte = u8::from_str_radix(&hex, 16)
+                    .ok()
+                    .filter(|_| hex.len() == 2)
+                    .with_context(|| format!("\\x needs two hex digits, got {:?}", hex))?;
+                bytes.push(byte);
+            }
+            Some(other) => anyhow::bail!("unknown escape \\{}", other),
+            None => anyhow::bail!("trailing backslash"),
+        }
+    }
+    Ok(bytes)
+}
+
+fn parse_auth_method(name: &str) -> Result<MethodKind> {
+    match name.parse() {
+        Ok(
+            method @ (MethodKind::None
+            | MethodKind::Password
+            | MethodKind::PublicKey
+            | MethodKind::KeyboardInteractive),
+        ) => Ok(method),
+        _ => anyhow::bail!(
+            "unknown auth method {} (expected none, password, publickey or keyboard-interactive)",
+            name
+        ),
+    }
+}
+
+fn reload(banner: Option<&Banner>, motd: Option<&Banner>) {
+    info!("Reloading configuration");
+    for (name, banner) in [("banner", banner), ("MOTD", motd)] {
+        if let Some(banner) = banner {
+            match banner.reload() {
+                Ok(()) => info!("Reloaded {}", name),
+                Err(e) => warn!(
+                    "Failed to reload {}, keeping the previous one: {:#}",
+                    name, e
+                ),
+            }
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
+}
 
-        // Set secure permissions (600)
-        std::fs::set_permissions(key_path, std::fs::Permissions::from_mode(0o600))
-            .context("Failed to set host key permissions")?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        info!("Generated and saved new host key");
-        Ok(key)
+    #[test]
+    fn max_output_rate_is_an_alias_for_max_output_bps() {
+        for flag in ["--max-output-bps", "--max-output-rate"] {
+            let args = Args::try_parse_from(["tuihost", "-c", "htop", flag, "4096"]).unwrap();
+            assert_eq!(args.max_output_bps, 4096);
+        }
     }
 }
@@ -1,55 +1,320 @@
+use crate::cgroup::Cgroup;
+use crate::sandbox::Chroot;
+use crate::server::CmdConfig;
 use anyhow::{Context, Result};
+use nix::sys::signal::{Signal, killpg};
+use nix::sys::termios::{
+    self, InputFlags, LocalFlags, OutputFlags, SetArg, SpecialCharacterIndices,
+};
+use nix::unistd::Pid;
 use pty_process::Size;
+use russh::Pty;
+use std::os::fd::AsRawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{debug, warn};
+
+// Mode value clients use to disable a special character (RFC 4254, section 8)
+const SSH_VDISABLE: u32 = 255;
+// How long to wait for more output once the child has exited
+const EXITED_DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Context on spawn errors caused by the system being unable to allocate a
+/// PTY, as opposed to the command failing to start.
+#[derive(Debug)]
+pub struct PtyUnavailable;
+
+impl std::fmt::Display for PtyUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Failed to create PTY")
+    }
+}
 
 pub struct PtySession {
     pty: pty_process::Pty,
-    _child: tokio::process::Child,
+    child: tokio::process::Child,
+    cgroup: Option<Cgroup>,
+    size: (u16, u16),
 }
 
 impl PtySession {
-    pub fn spawn(
-        command: &str,
-        args: &[String],
-        env: &[(String, String)],
-        cols: u16,
-        rows: u16,
-    ) -> Result<Self> {
-        let (pty, pts) = pty_process::open().context("Failed to create PTY")?;
+    pub fn spawn(config: &CmdConfig, modes: &[(Pty, u32)], cols: u16, rows: u16) -> Result<Self> {
+        let (pty, pts) = pty_process::open().context(PtyUnavailable)?;
 
+        // Sized before the child exists, so its first TIOCGWINSZ is already
+        // right and no startup SIGWINCH is needed
         pty.resize(Size::new(rows, cols))
             .context("Failed to set initial PTY size")?;
 
-        let child = pty_process::Command::new(command)
-            .args(args)
+        if !modes.is_empty() {
+            apply_terminal_modes(&pts, modes).context("Failed to apply terminal modes")?;
+        }
+
+        let mut cmd = pty_process::Command::new(&config.command)
+            .args(&config.args)
             .env_clear()
+            // For clients that didn't send a terminal type
             .env("TERM", "xterm-256color")
             .env("LANG", "en_US.UTF-8")
-            .envs(env.iter().cloned())
-            .spawn(pts)
-            .with_context(|| format!("Failed to spawn command: {}", command))?;
+            // Explicit --env values still win over the account's
+            .envs(config.run_as.iter().flat_map(|run_as| run_as.login_env()))
+            .envs(config.env.iter().cloned())
+            // Last resort if the session task is dropped without terminating it
+            .kill_on_drop(true);
+
+        // Where the command starts, as it sees it
+        let dir = if let Some(workdir) = &config.workdir {
+            anyhow::ensure!(
+                config.host_path(Path::new(workdir)).is_dir(),
+                "Working directory does not exist: {}",
+                workdir
+            );
+            Some(PathBuf::from(workdir))
+        } else if let Some(run_as) = &config.run_as {
+            // Like login(1), fall back to / rather than our own directory
+            let home = run_as
+                .home
+                .clone()
+                .filter(|home| config.host_path(home).is_dir());
+            Some(home.unwrap_or_else(|| PathBuf::from("/")))
+        } else {
+            None
+        };
+
+        // Inside a jail the directory can only be entered after the chroot
+        let chroot = match &config.chroot {
+            Some(root) => Some(Chroot::new(root, dir.as_deref().unwrap_or(Path::new("/")))?),
+            None => {
+                if let Some(dir) = &dir {
+                    cmd = cmd.current_dir(dir);
+                }
+                None
+            }
+        };
 
-        Ok(Self { pty, _child: child })
+        if let Some(run_as) = &config.run_as {
+            // The child needs to own its terminal once it drops privileges
+            nix::unistd::fchown(pts.as_raw_fd(), Some(run_as.uid), Some(run_as.gid))
+                .context("Failed to change PTY ownership")?;
+        }
+
+        if config.run_as.is_some() || !config.limits.is_empty() || chroot.is_some() {
+            let run_as = config.run_as.clone();
+            let limits = config.limits.clone();
+            // SAFETY: the closure only makes raw setrlimit/chroot/chdir/set*id
+            // syscalls on data it owns, which is async-signal-safe.
+            cmd = unsafe {
+                cmd.pre_exec(move || {
+                    limits.apply()?;
+                    if let Some(chroot) = &chroot {
+                        chroot.apply()?;
+                    }
+                    if let Some(run_as) = &run_as {
+                        run_as.apply()?;
+                    }
+                    Ok(())
+                })
+            };
+        }
+
+        let child = cmd.spawn(pts).with_context(|| match &config.chroot {
+            Some(root) => format!(
+                "Failed to spawn command {} in chroot {} (chroot needs root or CAP_SYS_CHROOT)",
+                config.command,
+                root.display()
+            ),
+            None => format!("Failed to spawn command: {}", config.command),
+        })?;
+
+        let cgroup = config.cgroup.as_ref().and_then(|limits| {
+            let pid = child.id()?;
+            limits
+                .attach(pid)
+                .map_err(|e| warn!("{:#}; running {} without cgroup limits", e, config.command))
+                .ok()
+        });
+
+        Ok(Self {
+            pty,
+            child,
+            cgroup,
+            size: (cols, rows),
+        })
     }
 
+    /// Splits into the output and input halves. The reader keeps the child
+    /// process handle; the writer only knows its pid, which the reader
+    /// clears once it has reaped the child.
     pub fn split(self) -> (PtyReader, PtyWriter) {
+        let pid = Arc::new(std::sync::Mutex::new(self.child.id()));
         let (reader, writer) = self.pty.into_split();
-        (PtyReader { reader }, PtyWriter { writer })
+        (
+            PtyReader {
+                reader,
+                child: self.child,
+                pid: pid.clone(),
+                exited: false,
+                _cgroup: self.cgroup,
+            },
+            PtyWriter {
+                writer,
+                pid,
+                size: self.size,
+            },
+        )
+    }
+}
+
+fn apply_terminal_modes(pts: &pty_process::Pts, modes: &[(Pty, u32)]) -> Result<()> {
+    let mut attrs = termios::tcgetattr(pts)?;
+
+    for &(mode, value) in modes {
+        let control_char = match mode {
+            Pty::VINTR => Some(SpecialCharacterIndices::VINTR),
+            Pty::VQUIT => Some(SpecialCharacterIndices::VQUIT),
+            Pty::VERASE => Some(SpecialCharacterIndices::VERASE),
+            Pty::VKILL => Some(SpecialCharacterIndices::VKILL),
+            Pty::VEOF => Some(SpecialCharacterIndices::VEOF),
+            Pty::VSTART => Some(SpecialCharacterIndices::VSTART),
+            Pty::VSTOP => Some(SpecialCharacterIndices::VSTOP),
+            Pty::VSUSP => Some(SpecialCharacterIndices::VSUSP),
+            _ => None,
+        };
+        if let Some(index) = control_char {
+            attrs.control_chars[index as usize] = if value == SSH_VDISABLE {
+                0
+            } else {
+                value as u8
+            };
+            continue;
+        }
+
+        let enabled = value != 0;
+        match mode {
+            Pty::ICRNL => attrs.input_flags.set(InputFlags::ICRNL, enabled),
+            Pty::IXON => attrs.input_flags.set(InputFlags::IXON, enabled),
+            Pty::IXOFF => attrs.input_flags.set(InputFlags::IXOFF, enabled),
+            Pty::IUTF8 => attrs.input_flags.set(InputFlags::IUTF8, enabled),
+            Pty::ISIG => attrs.local_flags.set(LocalFlags::ISIG, enabled),
+            Pty::ICANON => attrs.local_flags.set(LocalFlags::ICANON, enabled),
+            Pty::ECHO => attrs.local_flags.set(LocalFlags::ECHO, enabled),
+            Pty::ECHOE => attrs.local_flags.set(LocalFlags::ECHOE, enabled),
+            Pty::ECHOK => attrs.local_flags.set(LocalFlags::ECHOK, enabled),
+            Pty::ECHONL => attrs.local_flags.set(LocalFlags::ECHONL, enabled),
+            Pty::IEXTEN => attrs.local_flags.set(LocalFlags::IEXTEN, enabled),
+            Pty::OPOST => attrs.output_flags.set(OutputFlags::OPOST, enabled),
+            Pty::ONLCR => attrs.output_flags.set(OutputFlags::ONLCR, enabled),
+            _ => debug!("Ignoring unsupported terminal mode {:?}={}", mode, value),
+        }
     }
+
+    termios::tcsetattr(pts, SetArg::TCSANOW, &attrs)?;
+    Ok(())
 }
 
+/// Output half of a session, owning the child. The child is always reaped:
+/// by `read` or `wait` once it exits, by `terminate`, or, if the reader is
+/// dropped first, killed and left to tokio's orphan reaper.
 pub struct PtyReader {
     reader: pty_process::OwnedReadPty,
+    child: tokio::process::Child,
+    // Shared with the writer; None once the child is reaped, as its pid may
+    // then belong to another process
+    pid: Arc<std::sync::Mutex<Option<u32>>>,
+    exited: bool,
+    // Removed, killing anything left in it, once the reader goes
+    _cgroup: Option<Cgroup>,
 }
 
 impl PtyReader {
+    /// Reads the child's output. Also reaps the child as soon as it exits;
+    /// after that, output it left behind is drained and `Ok(0)` is returned
+    /// once the terminal goes quiet, even if a background process still
+    /// holds it open.
     pub async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.reader.read(buf).await
+        if !self.exited {
+            tokio::select! {
+                result = self.reader.read(buf) => return result,
+                status = self.child.wait() => {
+                    self.reaped();
+                    debug!("Child exited with {}", status?);
+                    self.exited = true;
+                }
+            }
+        }
+        match tokio::time::timeout(EXITED_DRAIN_TIMEOUT, self.reader.read(buf)).await {
+            Ok(result) => result,
+            Err(_) => Ok(0),
+        }
+    }
+
+    /// Sends SIGHUP to the child's process group, as a closing terminal would.
+    pub fn hangup(&self) -> Result<()> {
+        signal_group(self.child.id(), Signal::SIGHUP)
+    }
+
+    /// Waits up to `timeout` for the child to exit. `None` means it's still
+    /// running.
+    pub async fn wait(&mut self, timeout: Duration) -> Result<Option<ExitStatus>> {
+        match tokio::time::timeout(timeout, self.child.wait()).await {
+            Ok(status) => {
+                self.reaped();
+                Ok(Some(status.context("Failed to wait for child")?))
+            }
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Sends SIGHUP and SIGTERM to the child's process group and waits up to
+    /// `grace` for it to exit, then SIGKILLs the group and reaps the child.
+    /// A failed signal doesn't skip the wait, so the child never lingers as
+    /// a zombie.
+    pub async fn terminate(&mut self, grace: Duration) -> Result<()> {
+        if let Err(e) = self
+            .hangup()
+            .and_then(|()| signal_group(self.child.id(), Signal::SIGTERM))
+        {
+            debug!("{:#}", e);
+        }
+        if tokio::time::timeout(grace, self.child.wait())
+            .await
+            .is_err()
+        {
+            debug!("Child ignored SIGHUP/SIGTERM, killing it");
+            if let Err(e) = signal_group(self.child.id(), Signal::SIGKILL) {
+                // Fall back to killing just the child
+                debug!("{:#}", e);
+                self.child.start_kill().context("Failed to kill child")?;
+            }
+            self.child
+                .wait()
+                .await
+                .context("Failed to wait for child")?;
+        }
+        self.reaped();
+        Ok(())
+    }
+
+    fn reaped(&self) {
+        *self.pid.lock().unwrap() = None;
+    }
+}
+
+impl Drop for PtyReader {
+    // The child is killed and reaped in the background from here on
+    fn drop(&mut self) {
+        self.reaped();
     }
 }
 
 pub struct PtyWriter {
     writer: pty_process::OwnedWritePty,
+    pid: Arc<std::sync::Mutex<Option<u32>>>,
+    size: (u16, u16),
 }
 
 impl PtyWriter {
@@ -57,9 +322,151 @@ impl PtyWriter {
         self.writer.write_all(data).await
     }
 
-    pub fn resize(&self, cols: u16, rows: u16) -> Result<()> {
+    /// Writes as much of `data` as the child takes within `timeout` and
+    /// returns how many bytes that was. A child that stops reading fills
+    /// the terminal's buffer, so the rest would otherwise wait forever.
+    pub async fn write_within(&mut self, data: &[u8], timeout: Duration) -> std::io::Result<usize> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut written = 0;
+        while written < data.len() {
+            match tokio::time::timeout_at(deadline, self.writer.write(&data[written..])).await {
+                Ok(Ok(0)) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(Ok(n)) => written += n,
+                Ok(Err(e)) => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(written)
+    }
+
+    pub fn resize(&mut self, cols: u16, rows: u16) -> Result<()> {
         self.writer
             .resize(Size::new(rows, cols))
-            .context("Failed to resize PTY")
+            .context("Failed to resize PTY")?;
+        self.size = (cols, rows);
+        Ok(())
+    }
+
+    /// Current size as (cols, rows)
+    pub fn size(&self) -> (u16, u16) {
+        self.size
+    }
+
+    /// Asks the child to redraw by sending SIGWINCH to its process group.
+    /// The kernel only signals on an actual size change, so re-applying the
+    /// current size alone wouldn't reach the child.
+    pub fn refresh(&self) -> Result<()> {
+        signal_group(*self.pid.lock().unwrap(), Signal::SIGWINCH)
+    }
+
+    /// Sends `signal` to the child's process group.
+    pub fn signal(&self, signal: Signal) -> Result<()> {
+        signal_group(*self.pid.lock().unwrap(), signal)
+    }
+}
+
+/// Describes how a child exited, e.g. "exited with code 1" or "was killed by SIGSEGV"
+pub fn describe_exit(status: ExitStatus) -> String {
+    if let Some(code) = status.code() {
+        return format!("exited with code {}", code);
+    }
+    match status.signal().map(Signal::try_from) {
+        Some(Ok(signal)) => format!("was killed by {}", signal),
+        Some(Err(_)) => format!("was killed by signal {}", status.signal().unwrap_or(0)),
+        None => format!("exited ({})", status),
+    }
+}
+
+// The child is a session leader, so its pid is also its process group id
+fn signal_group(pid: Option<u32>, signal: Signal) -> Result<()> {
+    let pid = pid.context("Child process has exited")?;
+    killpg(Pid::from_raw(pid as i32), signal)
+        .with_context(|| format!("Failed to send {} to child", signal))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::reaped_within;
+    use std::time::Instant;
+
+    fn spawn(command: &str, args: &[&str]) -> (PtyReader, PtyWriter) {
+        let config = CmdConfig::builder()
+            .command(command)
+            .args(args.iter().copied())
+            .build()
+            .unwrap();
+        // Raw, like a TUI: a canonical terminal discards what doesn't fit
+        // in its line buffer rather than stalling the writer
+        let modes = [(Pty::ICANON, 0), (Pty::ECHO, 0)];
+        PtySession::spawn(&config, &modes, 80, 24).unwrap().split()
+    }
+
+    #[tokio::test]
+    async fn write_within_gives_up_on_a_child_that_stops_reading() {
+        let (mut reader, mut writer) = spawn("sleep", &["30"]);
+        let data = vec![b'x'; 1 << 20];
+        let started = Instant::now();
+        let written = writer
+            .write_within(&data, Duration::from_millis(200))
+            .await
+            .unwrap();
+        assert!(written < data.len(), "wrote all {} bytes", written);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        reader.terminate(Duration::from_secs(1)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn writer_stops_signalling_once_the_child_is_reaped() {
+        let (mut reader, writer) = spawn("sleep", &["30"]);
+        writer.signal(Signal::SIGTERM).unwrap();
+        let mut buf = [0; 64];
+        // EIO once the terminal closes, or Ok(0) if the child was reaped first
+        while reader.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+        reader.wait(Duration::from_secs(1)).await.unwrap();
+        assert!(writer.signal(Signal::SIGTERM).is_err());
+        assert!(writer.refresh().is_err());
+    }
+
+    #[tokio::test]
+    async fn writer_stops_signalling_once_the_reader_is_dropped() {
+        let (reader, writer) = spawn("sleep", &["30"]);
+        drop(reader);
+        assert!(writer.signal(Signal::SIGTERM).is_err());
+    }
+
+    #[tokio::test]
+    async fn write_within_writes_everything_a_child_takes() {
+        let (mut reader, mut writer) = spawn("cat", &[]);
+        let written = writer
+            .write_within(b"hello\n", Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(written, 6);
+        reader.terminate(Duration::from_secs(1)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn quickly_exiting_children_are_reaped() {
+        let (mut reader, _writer) = spawn("true", &[]);
+        let pid = reader.child.id().unwrap() as i32;
+        let mut buf = [0; 64];
+        while reader.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+        drop(reader);
+        assert!(reaped_within(pid, Duration::from_secs(1)).await);
+    }
+
+    #[tokio::test]
+    async fn reads_end_when_the_child_exits_under_a_background_process() {
+        let (mut reader, _writer) = spawn("sh", &["-c", "sleep 30 & echo done"]);
+        let pid = reader.child.id().unwrap() as i32;
+        let mut buf = [0; 64];
+        let read_to_end = async { while reader.read(&mut buf).await.is_ok_and(|n| n > 0) {} };
+        tokio::time::timeout(Duration::from_secs(5), read_to_end)
+            .await
+            .expect("read waited for the background process");
+        assert!(reader.wait(Duration::ZERO).await.unwrap().is_some());
+        // The background sleep is still in the child's process group
+        killpg(Pid::from_raw(pid), Signal::SIGKILL).unwrap();
     }
 }
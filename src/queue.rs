@@ -0,0 +1,68 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Holds connections that arrive while the server is full until a slot
+/// frees up, instead of turning them away at once. Connections that find
+/// the queue full, or are still waiting after `timeout`, are refused as
+/// before.
+#[derive(Debug)]
+pub struct ConnectionQueue {
+    capacity: usize,
+    timeout: Duration,
+    waiting: AtomicUsize,
+    slot_freed: Notify,
+}
+
+impl ConnectionQueue {
+    pub fn new(capacity: usize, timeout: Duration) -> Self {
+        Self {
+            capacity,
+            timeout,
+            waiting: AtomicUsize::new(0),
+            slot_freed: Notify::new(),
+        }
+    }
+
+    /// Connections currently waiting for a slot.
+    pub fn len(&self) -> usize {
+        self.waiting.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Waits while `is_full` says there's no slot, for as long as the queue
+    /// allows. Returns the time spent queued; the caller admits or refuses
+    /// the connection as usual afterwards.
+    pub async fn wait(&self, is_full: impl Fn() -> bool) -> Duration {
+        let started = Instant::now();
+        if !is_full() {
+            return Duration::ZERO;
+        }
+        if self.waiting.fetch_add(1, Ordering::SeqCst) >= self.capacity {
+            self.waiting.fetch_sub(1, Ordering::SeqCst);
+            return Duration::ZERO;
+        }
+        let deadline = started + self.timeout;
+        loop {
+            // A slot freed between the check and the wait leaves a permit
+            let notified = self.slot_freed.notified();
+            if !is_full() {
+                break;
+            }
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                break;
+            }
+        }
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+        started.elapsed()
+    }
+
+    // Wakes one waiting connection, or lets the next one through at once
+    pub(crate) fn slot_freed(&self) {
+        self.slot_freed.notify_one();
+    }
+}
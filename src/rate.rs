@@ -0,0 +1,102 @@
+use std::time::{Duration, Instant};
+
+/// Token bucket refilled at `rate` tokens per second. It holds at most one
+/// second's worth, so a burst after a quiet spell passes at full speed
+/// while the average stays at the rate.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(per_sec: u64) -> Self {
+        let rate = per_sec.max(1) as f64;
+        Self {
+            rate,
+            tokens: rate,
+            last: Instant::now(),
+        }
+    }
+
+    pub(crate) fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Takes `n` tokens if that many are left.
+    pub(crate) fn try_take(&mut self, n: u64) -> bool {
+        self.refill();
+        if self.tokens < n as f64 {
+            return false;
+        }
+        self.tokens -= n as f64;
+        true
+    }
+
+    /// Takes `n` tokens even if that runs the bucket into debt, returning
+    /// how long to wait until the debt is paid off.
+    pub(crate) fn take(&mut self, n: u64) -> Duration {
+        self.refill();
+        self.tokens -= n as f64;
+        if self.tokens < 0.0 {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_a_burst_of_one_seconds_worth() {
+        let mut bucket = TokenBucket::new(5);
+        for _ in 0..5 {
+            assert!(bucket.try_take(1));
+        }
+        assert!(!bucket.try_take(1));
+    }
+
+    #[test]
+    fn refills_at_the_rate() {
+        let mut bucket = TokenBucket::new(100);
+        assert!(bucket.try_take(100));
+        assert!(!bucket.try_take(1));
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(bucket.try_take(4));
+    }
+
+    #[test]
+    fn never_holds_more_than_one_second() {
+        let mut bucket = TokenBucket::new(10);
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(!bucket.try_take(11));
+        assert!(bucket.try_take(10));
+    }
+
+    #[test]
+    fn debt_is_paid_off_at_the_rate() {
+        let mut bucket = TokenBucket::new(1000);
+        assert_eq!(bucket.take(1000), Duration::ZERO);
+        let wait = bucket.take(500);
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn zero_rate_is_treated_as_one() {
+        let mut bucket = TokenBucket::new(0);
+        assert_eq!(bucket.rate(), 1.0);
+        assert!(bucket.try_take(1));
+        assert!(!bucket.try_take(1));
+    }
+}
@@ -0,0 +1,82 @@
+use nix::sys::socket::{SockaddrLike, SockaddrStorage};
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+// Longest a reverse lookup may take before it's given up on
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+// How long an answer, including "no name", is reused for the same address
+const CACHE_TTL: Duration = Duration::from_secs(300);
+// Most answers kept; expired ones are swept once it's reached
+const CACHE_MAX_LEN: usize = 4096;
+
+// A lookup's answer and when it was made
+type Answer = (Option<String>, Instant);
+
+// Answers by address, so a source that keeps reconnecting doesn't cost a
+// lookup each time
+static CACHE: LazyLock<Mutex<HashMap<IpAddr, Answer>>> = LazyLock::new(Mutex::default);
+
+/// The name `ip` reverse-resolves to, if it has one and the system resolver
+/// answers within [`LOOKUP_TIMEOUT`]. Answers are cached for [`CACHE_TTL`];
+/// timeouts and resolver errors aren't, so they're retried next time.
+pub(crate) async fn lookup_host(ip: IpAddr) -> Option<String> {
+    if let Some((host, looked_up)) = CACHE.lock().unwrap().get(&ip)
+        && looked_up.elapsed() < CACHE_TTL
+    {
+        return host.clone();
+    }
+    let lookup = tokio::task::spawn_blocking(move || getnameinfo(ip));
+    let host = match tokio::time::timeout(LOOKUP_TIMEOUT, lookup).await {
+        Ok(Ok(Ok(host))) => host,
+        Ok(Ok(Err(code))) => {
+            debug!("Reverse DNS lookup for {} failed: error {}", ip, code);
+            return None;
+        }
+        Ok(Err(e)) => {
+            debug!("Reverse DNS lookup for {} failed: {}", ip, e);
+            return None;
+        }
+        Err(_) => {
+            debug!("Reverse DNS lookup for {} timed out", ip);
+            return None;
+        }
+    };
+    let mut cache = CACHE.lock().unwrap();
+    if cache.len() >= CACHE_MAX_LEN {
+        cache.retain(|_, (_, looked_up)| looked_up.elapsed() < CACHE_TTL);
+    }
+    if cache.len() < CACHE_MAX_LEN {
+        cache.insert(ip, (host.clone(), Instant::now()));
+    }
+    host
+}
+
+// Ok(None) when the address has no name; Err with the EAI_* code when the
+// resolver couldn't say
+fn getnameinfo(ip: IpAddr) -> Result<Option<String>, i32> {
+    let addr = SockaddrStorage::from(SocketAddr::new(ip, 0));
+    let mut host = [0 as libc::c_char; libc::NI_MAXHOST as usize];
+    // NI_NAMEREQD fails instead of returning the address as text
+    let result = unsafe {
+        libc::getnameinfo(
+            addr.as_ptr(),
+            addr.len(),
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    match result {
+        0 => {}
+        libc::EAI_NONAME => return Ok(None),
+        code => return Err(code),
+    }
+    let host = unsafe { CStr::from_ptr(host.as_ptr()) };
+    Ok(Some(host.to_string_lossy().into_owned()))
+}
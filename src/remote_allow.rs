@@ -0,0 +1,135 @@
+use crate::access::parse_net;
+use anyhow::{Context, Result, bail};
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+
+// Whole request, from connect to the last byte of the body
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_RESPONSE_BYTES: u64 = 1024 * 1024;
+
+/// Allow list of CIDRs fetched over HTTP, one per line (blank lines and `#`
+/// comments are skipped). A failed refresh keeps the previous list.
+#[derive(Debug)]
+pub struct RemoteAllowList {
+    url: String,
+    nets: RwLock<Arc<Vec<IpNet>>>,
+}
+
+impl RemoteAllowList {
+    /// Fetches the list once; fails if it can't be fetched, parsed, or is
+    /// empty. Only plain `http://` URLs are supported.
+    pub async fn load(url: impl Into<String>) -> Result<Self> {
+        let url = url.into();
+        let nets = fetch(&url).await?;
+        Ok(Self {
+            url,
+            nets: RwLock::new(Arc::new(nets)),
+        })
+    }
+
+    /// Fetches the list again, keeping the current one on failure.
+    pub async fn refresh(&self) {
+        match fetch(&self.url).await {
+            Ok(nets) => {
+                let count = nets.len();
+                let previous = std::mem::replace(&mut *self.nets.write().unwrap(), Arc::new(nets));
+                if previous.len() != count {
+                    info!("Remote allow list now has {} entries", count);
+                }
+            }
+            Err(e) => warn!(
+                "Failed to refresh remote allow list, keeping the previous one: {:#}",
+                e
+            ),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nets.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns why `ip` is refused, or `None` if the list contains it.
+    pub fn check(&self, ip: IpAddr) -> Option<String> {
+        let ip = ip.to_canonical();
+        let nets = self.nets.read().unwrap().clone();
+        (!nets.iter().any(|net| net.contains(&ip))).then(|| "not in remote allow list".to_string())
+    }
+}
+
+async fn fetch(url: &str) -> Result<Vec<IpNet>> {
+    let body = tokio::time::timeout(FETCH_TIMEOUT, http_get(url))
+        .await
+        .with_context(|| format!("Timed out fetching {}", url))?
+        .with_context(|| format!("Failed to fetch {}", url))?;
+
+    let mut nets = Vec::new();
+    for (number, line) in body.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let net = parse_net(line)
+            .map_err(anyhow::Error::msg)
+            .with_context(|| format!("{} line {}", url, number + 1))?;
+        nets.push(net);
+    }
+    // An empty answer is far more likely a broken service than a wish to
+    // lock everyone out
+    if nets.is_empty() {
+        bail!("{} returned no addresses", url);
+    }
+    Ok(nets)
+}
+
+// Minimal HTTP/1.0 GET, so responses are never chunked
+async fn http_get(url: &str) -> Result<String> {
+    let Some(rest) = url.strip_prefix("http://") else {
+        bail!("only http:// URLs are supported");
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    let address = if authority
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| !port.contains(']'))
+    {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+
+    let mut stream = TcpStream::connect(&address).await?;
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: tuihost\r\nConnection: close\r\n\r\n",
+        path, authority
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    (&mut stream)
+        .take(MAX_RESPONSE_BYTES + 1)
+        .read_to_end(&mut response)
+        .await?;
+    if response.len() as u64 > MAX_RESPONSE_BYTES {
+        bail!("response is larger than {} bytes", MAX_RESPONSE_BYTES);
+    }
+    let response = String::from_utf8(response).context("response isn't UTF-8")?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .context("malformed HTTP response")?;
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        bail!("server answered {}", status);
+    }
+    Ok(body.to_string())
+}
@@ -0,0 +1,306 @@
+use anyhow::{Context, Result, bail};
+use nix::sys::resource::{Resource, getrlimit, setrlimit};
+use nix::unistd::{Gid, Group, Uid, User};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Target account the spawned command runs as.
+#[derive(Debug, Clone)]
+pub struct RunAs {
+    pub user: String,
+    pub uid: Uid,
+    pub gid: Gid,
+    pub groups: Vec<Gid>,
+    /// Home directory from the passwd entry, if it has one that exists
+    pub home: Option<PathBuf>,
+    /// Login shell from the passwd entry, if set
+    pub shell: Option<PathBuf>,
+}
+
+impl RunAs {
+    /// Resolves `user[:group]`. Without an explicit group the user's primary
+    /// group is used; supplementary groups always come from the user's entry.
+    pub fn resolve(spec: &str) -> Result<Self> {
+        let (user_name, group_name) = match spec.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (spec, None),
+        };
+
+        let user = User::from_name(user_name)
+            .with_context(|| format!("Failed to look up user {}", user_name))?
+            .with_context(|| format!("Unknown user: {}", user_name))?;
+
+        let gid = match group_name {
+            Some(name) => {
+                Group::from_name(name)
+                    .with_context(|| format!("Failed to look up group {}", name))?
+                    .with_context(|| format!("Unknown group: {}", name))?
+                    .gid
+            }
+            None => user.gid,
+        };
+
+        let c_name = CString::new(user.name.as_str()).context("Invalid user name")?;
+        let groups = nix::unistd::getgrouplist(&c_name, gid)
+            .with_context(|| format!("Failed to list groups for {}", user.name))?;
+
+        // Accounts like nobody often point at a home that doesn't exist
+        let home = Some(user.dir).filter(|dir| dir.is_dir());
+        let shell = Some(user.shell).filter(|shell| !shell.as_os_str().is_empty());
+
+        Ok(Self {
+            user: user.name,
+            uid: user.uid,
+            gid,
+            groups,
+            home,
+            shell,
+        })
+    }
+
+    /// The variables a login as this user would set.
+    pub(crate) fn login_env(&self) -> Vec<(String, String)> {
+        let mut env = vec![
+            ("USER".to_string(), self.user.clone()),
+            ("LOGNAME".to_string(), self.user.clone()),
+        ];
+        if let Some(home) = &self.home {
+            env.push(("HOME".to_string(), home.display().to_string()));
+        }
+        if let Some(shell) = &self.shell {
+            env.push(("SHELL".to_string(), shell.display().to_string()));
+        }
+        env
+    }
+
+    // Runs in the forked child: drop supplementary groups first, then the
+    // group, then the user, since the later calls remove the right to the
+    // earlier ones.
+    pub(crate) fn apply(&self) -> std::io::Result<()> {
+        nix::unistd::setgroups(&self.groups)?;
+        nix::unistd::setgid(self.gid)?;
+        nix::unistd::setuid(self.uid)?;
+        Ok(())
+    }
+}
+
+/// A chroot jail and the directory inside it the command starts in.
+#[derive(Debug, Clone)]
+pub(crate) struct Chroot {
+    root: CString,
+    dir: CString,
+}
+
+impl Chroot {
+    pub(crate) fn new(root: &Path, dir: &Path) -> Result<Self> {
+        let c_path = |path: &Path| {
+            CString::new(path.as_os_str().as_bytes())
+                .with_context(|| format!("Invalid path: {}", path.display()))
+        };
+        Ok(Self {
+            root: c_path(root)?,
+            dir: c_path(dir)?,
+        })
+    }
+
+    // Runs in the forked child, before privileges are dropped since
+    // chroot needs them. The chdir keeps the old working directory, which
+    // is outside the jail, from staying reachable.
+    pub(crate) fn apply(&self) -> std::io::Result<()> {
+        // SAFETY: both pointers come from CStrings owned by self
+        if unsafe { libc::chroot(self.root.as_ptr()) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if unsafe { libc::chdir(self.dir.as_ptr()) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// A soft and hard limit pair.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RLimit {
+    pub soft: u64,
+    pub hard: u64,
+}
+
+impl RLimit {
+    /// Parses `N` (soft and hard both N) or `SOFT:HARD`.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let parse_value = |value: &str| -> Result<u64> {
+            match value.parse() {
+                Ok(0) => bail!("limit must be at least 1"),
+                Ok(value) => Ok(value),
+                Err(_) => bail!("invalid limit: {}", value),
+            }
+        };
+        let limit = match spec.split_once(':') {
+            Some((soft, hard)) => Self {
+                soft: parse_value(soft)?,
+                hard: parse_value(hard)?,
+            },
+            None => {
+                let value = parse_value(spec)?;
+                Self {
+                    soft: value,
+                    hard: value,
+                }
+            }
+        };
+        if limit.soft > limit.hard {
+            bail!(
+                "soft limit {} exceeds hard limit {}",
+                limit.soft,
+                limit.hard
+            );
+        }
+        Ok(limit)
+    }
+}
+
+/// Per-process resource limits applied to the spawned command.
+#[derive(Debug, Clone, Default)]
+pub struct ResourceLimits {
+    pub address_space: Option<RLimit>,
+    pub nproc: Option<RLimit>,
+    pub cpu_secs: Option<RLimit>,
+    pub nofile: Option<RLimit>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        self.limits().iter().all(|(_, _, limit)| limit.is_none())
+    }
+
+    /// Fails if a hard limit is above what this process may set, so a bad
+    /// value is caught at startup rather than on every spawn.
+    pub fn check(&self) -> Result<()> {
+        if Uid::effective().is_root() {
+            return Ok(());
+        }
+        for (resource, name, limit) in self.limits() {
+            let Some(limit) = limit else { continue };
+            let (_, max) =
+                getrlimit(resource).with_context(|| format!("Failed to read current {}", name))?;
+            if limit.hard > max {
+                bail!(
+                    "{} hard limit {} exceeds the current hard limit {}",
+                    name,
+                    limit.hard,
+                    max
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn limits(&self) -> [(Resource, &'static str, Option<RLimit>); 4] {
+        [
+            (Resource::RLIMIT_AS, "RLIMIT_AS", self.address_space),
+            (Resource::RLIMIT_NPROC, "RLIMIT_NPROC", self.nproc),
+            (Resource::RLIMIT_CPU, "RLIMIT_CPU", self.cpu_secs),
+            (Resource::RLIMIT_NOFILE, "RLIMIT_NOFILE", self.nofile),
+        ]
+    }
+
+    // Runs in the forked child, before privileges are dropped
+    pub(crate) fn apply(&self) -> std::io::Result<()> {
+        for (resource, _, limit) in self.limits() {
+            if let Some(limit) = limit {
+                setrlimit(resource, limit.soft, limit.hard)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pty::PtySession;
+    use crate::server::CmdConfig;
+
+    #[test]
+    fn resolves_a_user_and_its_primary_group() {
+        let run_as = RunAs::resolve("root").unwrap();
+        assert_eq!(run_as.user, "root");
+        assert_eq!(run_as.uid, Uid::from_raw(0));
+        assert_eq!(run_as.gid, Gid::from_raw(0));
+        assert!(run_as.groups.contains(&Gid::from_raw(0)));
+    }
+
+    #[test]
+    fn an_explicit_group_replaces_the_primary_one() {
+        let nogroup = nogroup();
+        let run_as = RunAs::resolve(&format!("root:{}", nogroup.name)).unwrap();
+        assert_eq!(run_as.uid, Uid::from_raw(0));
+        assert_eq!(run_as.gid, nogroup.gid);
+    }
+
+    #[test]
+    fn unknown_users_and_groups_are_errors() {
+        let err = RunAs::resolve("no-such-user-tuihost").unwrap_err();
+        assert_eq!(err.to_string(), "Unknown user: no-such-user-tuihost");
+        let err = RunAs::resolve("root:no-such-group-tuihost").unwrap_err();
+        assert_eq!(err.to_string(), "Unknown group: no-such-group-tuihost");
+    }
+
+    #[tokio::test]
+    async fn the_command_runs_as_the_target_user() {
+        // Switching users needs root
+        if !Uid::effective().is_root() {
+            return;
+        }
+        let nogroup = nogroup();
+        let run_as = RunAs::resolve(&format!("nobody:{}", nogroup.name)).unwrap();
+        let config = CmdConfig::builder()
+            .command("cat")
+            .arg("/proc/self/status")
+            .env("PATH", "/usr/bin:/bin")
+            .run_as(run_as.clone())
+            .build()
+            .unwrap();
+        let (mut reader, _writer) = PtySession::spawn(&config, &[], 80, 24).unwrap().split();
+        let mut status = Vec::new();
+        let mut buf = [0; 4096];
+        // EIO once the terminal closes
+        while let Ok(n @ 1..) = reader.read(&mut buf).await {
+            status.extend_from_slice(&buf[..n]);
+        }
+        let status = String::from_utf8_lossy(&status);
+        let ids = |field: &str| -> Vec<u32> {
+            let line = status
+                .lines()
+                .find_map(|line| line.strip_prefix(field))
+                .unwrap_or_else(|| panic!("no {} in {:?}", field, status));
+            line.split_whitespace()
+                .map(|id| id.parse().unwrap())
+                .collect()
+        };
+        assert_eq!(ids("Uid:"), [run_as.uid.as_raw(); 4]);
+        assert_eq!(ids("Gid:"), [nogroup.gid.as_raw(); 4]);
+    }
+
+    fn nogroup() -> Group {
+        ["nogroup", "nobody"]
+            .into_iter()
+            .find_map(|name| Group::from_name(name).ok().flatten())
+            .expect("no nogroup or nobody group")
+    }
+
+    #[test]
+    fn login_env_comes_from_the_passwd_entry() {
+        let user = User::from_name("root").unwrap().unwrap();
+        let env = RunAs::resolve("root").unwrap().login_env();
+        for (key, value) in [
+            ("USER", "root".to_string()),
+            ("LOGNAME", "root".to_string()),
+            ("HOME", user.dir.display().to_string()),
+            ("SHELL", user.shell.display().to_string()),
+        ] {
+            assert!(env.contains(&(key.to_string(), value)), "{:?}", env);
+        }
+    }
+}
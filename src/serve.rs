@@ -0,0 +1,285 @@
+use crate::server::TuiSshServer;
+use russh::Disconnect;
+use russh::server::Config;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::{Notify, broadcast};
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+// Longest a refused client is given to take its refusal line
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Accepts connections on `listener` until `shutdown` sends, running each
+/// one in its own task. Several listeners can share one server. Sessions
+/// still open at shutdown get the sent reason as their disconnect message.
+///
+/// Prefer this to russh's `run_on_socket`, which can only reject clients
+/// after a full handshake and knows nothing of the server's connection
+/// queue, [handshake timeout](crate::TuiSshServerBuilder::handshake_timeout),
+/// [connection age limit](crate::TuiSshServerBuilder::max_connection_age) or
+/// [kills](crate::SessionList::kill) of sessions that have no channel yet.
+pub async fn serve(
+    server: Arc<Mutex<TuiSshServer>>,
+    config: Arc<Config>,
+    listener: TcpListener,
+    shutdown: broadcast::Sender<String>,
+) {
+    let (handshake_timeout, max_connection_age) = {
+        let server = server.lock().unwrap();
+        (server.handshake_timeout(), server.max_connection_age())
+    };
+    let mut shutdown_rx = shutdown.subscribe();
+    loop {
+        let (stream, peer_addr) = tokio::select! {
+            _ = shutdown_rx.recv() => return,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Usually fd exhaustion; back off instead of spinning
+                    warn!("Failed to accept connection: {}", e);
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            },
+        };
+
+        if config.nodelay
+            && let Err(e) = stream.set_nodelay(true)
+        {
+            warn!("Failed to set TCP_NODELAY for {}: {}", peer_addr, e);
+        }
+
+        // Refused clients are turned away before any key exchange, so they
+        // cost no crypto
+        let screened = server.lock().unwrap().screen_client(Some(peer_addr));
+        let session = match screened {
+            Ok(session) => session,
+            Err(handler) => {
+                let reason = handler.rejection().unwrap_or_default().to_string();
+                tokio::spawn(refuse(stream, reason));
+                continue;
+            }
+        };
+
+        let (stream, socket) = match handshake_timeout {
+            Some(_) => match clone_stream(stream) {
+                Ok((stream, socket)) => (stream, Some(socket)),
+                Err(e) => {
+                    warn!("Failed to set up connection from {}: {}", peer_addr, e);
+                    continue;
+                }
+            },
+            None => (stream, None),
+        };
+
+        let server = server.clone();
+        let config = config.clone();
+        let mut shutdown_rx = shutdown.subscribe();
+        tokio::spawn(async move {
+            // Holds the connection while the server is full, if it queues
+            let queue = server.lock().unwrap().connection_queue();
+            if let Some(queue) = queue {
+                let waited = queue.wait(|| server.lock().unwrap().is_full()).await;
+                if !waited.is_zero() {
+                    info!(
+                        "{} waited {}ms for a slot ({} still queued)",
+                        peer_addr,
+                        waited.as_millis(),
+                        queue.len()
+                    );
+                }
+            }
+            let mut handler = server.lock().unwrap().admit_client(session);
+            if let Some(reason) = handler.rejection() {
+                refuse(stream, reason.to_string()).await;
+                return;
+            }
+            if let Ok(local_addr) = stream.local_addr() {
+                handler.set_listen_addr(local_addr);
+            }
+            // Before the handshake, so every log line names the host
+            if !handler.resolve_client_host().await {
+                return;
+            }
+            let authenticated = handler.authenticated();
+            let killed = handler.killed();
+            let accepted_at = Instant::now();
+            let deadline = handshake_timeout.map(|timeout| accepted_at + timeout);
+            let expires = max_connection_age.map(|age| accepted_at + age);
+            let setup = russh::server::run_stream(config, stream, handler);
+            let setup = match deadline {
+                Some(deadline) => match tokio::time::timeout_at(deadline, setup).await {
+                    Ok(setup) => setup,
+                    Err(_) => {
+                        debug!("Dropping {}: handshake timed out", peer_addr);
+                        return;
+                    }
+                },
+                None => setup.await,
+            };
+            let session = match setup {
+                Ok(session) => session,
+                Err(e) => {
+                    debug!("Connection setup failed for {}: {}", peer_addr, e);
+                    return;
+                }
+            };
+            let handle = session.handle();
+            tokio::select! {
+                reason = shutdown_rx.recv() => {
+                    let reason = reason.unwrap_or_default();
+                    let _ = handle
+                        .disconnect(Disconnect::ByApplication, reason, "en".to_string())
+                        .await;
+                }
+                _ = handshake_expired(authenticated, deadline) => {
+                    debug!("Dropping {}: not authenticated before the handshake timeout", peer_addr);
+                    // The session ignores its handle during key exchange, so
+                    // close the socket under it instead
+                    if let Some(socket) = socket {
+                        let _ = socket.shutdown(std::net::Shutdown::Both);
+                    }
+                }
+                _ = killed.notified() => {
+                    info!("Disconnecting {}: killed by an administrator", peer_addr);
+                    let _ = handle
+                        .disconnect(
+                            Disconnect::ByApplication,
+                            "disconnected by the administrator".to_string(),
+                            "en".to_string(),
+                        )
+                        .await;
+                }
+                _ = expire_at(expires) => {
+                    info!(
+                        "Disconnecting {}: connection reached the maximum age of {}s",
+                        peer_addr,
+                        accepted_at.elapsed().as_secs()
+                    );
+                    let _ = handle
+                        .disconnect(
+                            Disconnect::ByApplication,
+                            "maximum connection age reached".to_string(),
+                            "en".to_string(),
+                        )
+                        .await;
+                }
+                result = session => {
+                    if let Err(e) = result {
+                        debug!("Connection from {} closed with error: {}", peer_addr, e);
+                    }
+                }
+            }
+        });
+    }
+}
+
+// Tells a refused client why in a line ahead of the SSH version string,
+// which clients log, then closes the connection without a handshake
+async fn refuse(mut stream: tokio::net::TcpStream, reason: String) {
+    // Other lines must not look like a version string (RFC 4253, section 4.2)
+    let reason = reason.lines().next().unwrap_or_default();
+    let reason = reason.strip_prefix("SSH-").unwrap_or(reason);
+    let refusal = async {
+        if !reason.is_empty() {
+            stream
+                .write_all(format!("{}\r\n", reason).as_bytes())
+                .await?;
+        }
+        stream.shutdown().await?;
+        // Closing with the client's version string unread would reset the
+        // connection, which can discard the line
+        let mut buf = [0; 1024];
+        while stream.read(&mut buf).await? > 0 {}
+        std::io::Result::Ok(())
+    };
+    let _ = tokio::time::timeout(REFUSAL_TIMEOUT, refusal).await;
+}
+
+// A second handle on the connection's socket, for closing it from outside
+// the session
+fn clone_stream(
+    stream: tokio::net::TcpStream,
+) -> std::io::Result<(tokio::net::TcpStream, std::net::TcpStream)> {
+    let stream = stream.into_std()?;
+    let socket = stream.try_clone()?;
+    Ok((tokio::net::TcpStream::from_std(stream)?, socket))
+}
+
+// Resolves if the client hasn't authenticated by `deadline`; never otherwise
+async fn handshake_expired(authenticated: Arc<Notify>, deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline)
+            if tokio::time::timeout_at(deadline, authenticated.notified())
+                .await
+                .is_err() => {}
+        _ => std::future::pending().await,
+    }
+}
+
+// Resolves at `at`; never without one
+async fn expire_at(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithms::Algorithms;
+    use crate::server::{CmdConfig, create_config, generate_host_key};
+    use crate::testing::TestServer;
+    use russh::keys::Algorithm;
+
+    #[tokio::test]
+    async fn sessions_run_end_to_end() {
+        let command = CmdConfig::builder()
+            .command("sh")
+            .arg("-c")
+            .arg("echo hello from tuihost")
+            .build()
+            .unwrap();
+        let server = TuiSshServer::builder().command(command).build().unwrap();
+        let server = TestServer::start(server).await;
+        let mut shell = server.shell("alice").await;
+        shell.read_to_end().await;
+        assert!(
+            shell.text().contains("hello from tuihost"),
+            "{:?}",
+            shell.text()
+        );
+    }
+
+    #[tokio::test]
+    async fn serve_returns_on_shutdown() {
+        let server = TuiSshServer::builder()
+            .command(CmdConfig::new("true"))
+            .build()
+            .unwrap();
+        let host_key = generate_host_key(Algorithm::Ed25519).unwrap();
+        let config = create_config(vec![host_key], 0, &Algorithms::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (shutdown, _) = broadcast::channel(1);
+        let serving = tokio::spawn(serve(
+            Arc::new(Mutex::new(server)),
+            Arc::new(config),
+            listener,
+            shutdown.clone(),
+        ));
+        // A shutdown sent before serve subscribes would be missed
+        while shutdown.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+        shutdown.send("test over".to_string()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), serving)
+            .await
+            .expect("serve ignored shutdown")
+            .unwrap();
+    }
+}
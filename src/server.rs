@@ -1,48 +1,673 @@
+use crate::access::{AcceptRateLimiter, AccessList, BanList};
+use crate::algorithms::Algorithms;
+use crate::audit::AuditLog;
+use crate::auth::{AllowAllAuthenticator, Authenticator};
+use crate::banner::Banner;
+use crate::broadcast::Broadcaster;
+use crate::cgroup::CgroupLimits;
+use crate::events::{EventObserver, SessionInfo};
+use crate::geoip::CountryFilter;
 use crate::handler::SessionHandler;
+use crate::honeypot::HoneypotLog;
+use crate::hooks::SessionHooks;
+use crate::queue::ConnectionQueue;
+use crate::remote_allow::RemoteAllowList;
+use crate::sandbox::{ResourceLimits, RunAs};
+use crate::sessions::{ActiveSession, SessionList};
+use crate::shared::SessionRegistry;
+use crate::totp::TotpSecrets;
+use anyhow::{Context, Result};
+use russh::keys::ssh_key::private::{KeypairData, RsaKeypair};
 use russh::keys::{Algorithm, PrivateKey};
 use russh::server::{Config, Server};
+use russh::{MethodKind, MethodSet};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Duration;
-use tracing::{info, warn};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+
+pub const DEFAULT_FULL_MESSAGE: &str = "Server full, try again later";
+pub const DEFAULT_PTY_ERROR_MESSAGE: &str =
+    "Server temporarily unable to allocate a terminal, please retry";
+
+/// Expanded per session in the command, its arguments and `--env` values.
+pub const PLACEHOLDERS: &[&str] = &[
+    "{user}",
+    "{client_ip}",
+    "{client_port}",
+    "{session_id}",
+    "{cols}",
+    "{rows}",
+    "{term}",
+];
+
+// Size of generated RSA host keys
+const RSA_HOST_KEY_BITS: usize = 3072;
+// How often `idle` checks whether the last session is gone
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 #[derive(Debug, Clone)]
 pub struct CmdConfig {
     pub command: String,
     pub args: Vec<String>,
     pub env: Vec<(String, String)>,
+    pub workdir: Option<String>,
+    pub run_as: Option<RunAs>,
+    pub limits: ResourceLimits,
+    /// Directory the command is jailed in; `command` and `workdir` are
+    /// paths inside it
+    pub chroot: Option<PathBuf>,
+    /// Kernel-enforced limits through a cgroup per command run
+    pub cgroup: Option<CgroupLimits>,
+}
+
+impl CmdConfig {
+    /// A command with no arguments, extra env, or sandboxing.
+    pub fn new(command: impl Into<String>) -> Self {
+        Self {
+            command: command.into(),
+            args: Vec::new(),
+            env: Vec::new(),
+            workdir: None,
+            run_as: None,
+            limits: ResourceLimits::default(),
+            chroot: None,
+            cgroup: None,
+        }
+    }
+
+    pub fn builder() -> CmdConfigBuilder {
+        CmdConfigBuilder::default()
+    }
+
+    /// Finds the executable the command runs, searching `PATH` for bare
+    /// names. Fails if it doesn't exist or isn't executable, so a typo is
+    /// caught before any client connects.
+    pub fn resolve(&self) -> Result<PathBuf> {
+        // Can't be checked until the session is known
+        if PLACEHOLDERS
+            .iter()
+            .any(|token| self.command.contains(token))
+        {
+            return Ok(PathBuf::from(&self.command));
+        }
+        if !self.command.contains('/') {
+            // The child sees the overridden PATH if one is set
+            let search = self
+                .env
+                .iter()
+                .rev()
+                .find(|(key, _)| key == "PATH")
+                .map(|(_, value)| value.into())
+                .or_else(|| std::env::var_os("PATH"))
+                .unwrap_or_default();
+            return std::env::split_paths(&search)
+                .map(|dir| self.host_path(&dir.join(&self.command)))
+                .find(|path| is_executable(path))
+                .with_context(|| format!("Command not found in PATH: {}", self.command));
+        }
+
+        let mut path = PathBuf::from(&self.command);
+        if path.is_relative()
+            && let Some(workdir) = &self.workdir
+        {
+            // Can't be checked until the username is known
+            if workdir.contains("{user}") {
+                return Ok(path);
+            }
+            path = Path::new(workdir).join(path);
+        }
+        let path = self.host_path(&path);
+        let metadata = std::fs::metadata(&path)
+            .with_context(|| format!("Command not found: {}", path.display()))?;
+        anyhow::ensure!(
+            metadata.is_file() && metadata.permissions().mode() & 0o111 != 0,
+            "Command is not executable: {}",
+            path.display()
+        );
+        Ok(path)
+    }
+
+    /// Where a path as the command sees it is on the host, which differs
+    /// only under [`chroot`](Self::chroot).
+    pub fn host_path(&self, path: &Path) -> PathBuf {
+        match &self.chroot {
+            Some(root) => root.join(path.strip_prefix("/").unwrap_or(path)),
+            None => path.to_path_buf(),
+        }
+    }
+}
+
+fn warn_unknown_placeholders(config: &CmdConfig) {
+    let values = std::iter::once(&config.command)
+        .chain(&config.args)
+        .chain(config.env.iter().map(|(_, value)| value));
+    for value in values {
+        let mut rest = value.as_str();
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                break;
+            };
+            let token = &rest[start..=start + len];
+            if !PLACEHOLDERS.contains(&token) {
+                warn!(
+                    "Unknown placeholder {} in {:?} is passed as is",
+                    token, value
+                );
+            }
+            rest = &rest[start + len + 1..];
+        }
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+/// Chainable alternative to filling in [`CmdConfig`] by hand.
+#[derive(Debug, Default)]
+pub struct CmdConfigBuilder {
+    command: Option<String>,
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+    workdir: Option<String>,
+    run_as: Option<RunAs>,
+    limits: ResourceLimits,
+    chroot: Option<PathBuf>,
+    cgroup: Option<CgroupLimits>,
+}
+
+impl CmdConfigBuilder {
+    /// [`PLACEHOLDERS`] expand per session.
+    pub fn command(mut self, command: impl Into<String>) -> Self {
+        self.command = Some(command.into());
+        self
+    }
+
+    /// [`PLACEHOLDERS`] expand per session.
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// [`PLACEHOLDERS`] in the value expand per session.
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+
+    /// `{user}` expands to the SSH username.
+    pub fn workdir(mut self, workdir: impl Into<String>) -> Self {
+        self.workdir = Some(workdir.into());
+        self
+    }
+
+    pub fn run_as(mut self, run_as: RunAs) -> Self {
+        self.run_as = Some(run_as);
+        self
+    }
+
+    pub fn limits(mut self, limits: ResourceLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Requires root or CAP_SYS_CHROOT; the command must exist inside the jail.
+    pub fn chroot(mut self, root: impl Into<PathBuf>) -> Self {
+        self.chroot = Some(root.into());
+        self
+    }
+
+    /// Call [`CgroupLimits::prepare`] once before the first spawn.
+    pub fn cgroup(mut self, cgroup: CgroupLimits) -> Self {
+        self.cgroup = Some(cgroup);
+        self
+    }
+
+    pub fn build(self) -> Result<CmdConfig> {
+        let command = self
+            .command
+            .filter(|command| !command.is_empty())
+            .context("CmdConfig needs a command")?;
+        Ok(CmdConfig {
+            command,
+            args: self.args,
+            env: self.env,
+            workdir: self.workdir,
+            run_as: self.run_as,
+            limits: self.limits,
+            chroot: self.chroot,
+            cgroup: self.cgroup,
+        })
+    }
+}
+
+/// Commands to run, selected by the authenticated username.
+#[derive(Debug, Clone)]
+pub struct CommandMap {
+    pub default: Option<CmdConfig>,
+    pub users: HashMap<String, CmdConfig>,
+    /// Variables added to, or replacing those in, a user's command env
+    pub user_env: HashMap<String, Vec<(String, String)>>,
+    /// Commands picked by logging in as `user+app`, ahead of `users`
+    pub apps: HashMap<String, CmdConfig>,
+}
+
+impl CommandMap {
+    pub fn for_user(&self, user: &str) -> Option<&CmdConfig> {
+        self.users.get(user).or(self.default.as_ref())
+    }
+
+    /// The app's command if one was picked, otherwise the user's.
+    pub fn for_session(&self, user: &str, app: Option<&str>) -> Option<&CmdConfig> {
+        match app.and_then(|app| self.apps.get(app)) {
+            Some(config) => Some(config),
+            None => self.for_user(user),
+        }
+    }
+
+    /// The user's env overlay; empty for users without one.
+    pub fn env_for_user(&self, user: &str) -> &[(String, String)] {
+        self.user_env
+            .get(user)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Checks that every command resolves to an executable, and warns
+    /// about placeholders that won't be expanded.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(config) = &self.default {
+            let path = config.resolve()?;
+            debug!("Command {} resolves to {}", config.command, path.display());
+            warn_unknown_placeholders(config);
+        }
+        for (user, config) in &self.users {
+            let path = config
+                .resolve()
+                .with_context(|| format!("Invalid command for user {}", user))?;
+            debug!("Command {} resolves to {}", config.command, path.display());
+            warn_unknown_placeholders(config);
+        }
+        for (app, config) in &self.apps {
+            let path = config
+                .resolve()
+                .with_context(|| format!("Invalid command for app {}", app))?;
+            debug!("Command {} resolves to {}", config.command, path.display());
+            warn_unknown_placeholders(config);
+        }
+        Ok(())
+    }
+}
+
+/// How often a command that exits may be re-run within a session
+#[derive(Debug, Clone)]
+pub struct RespawnPolicy {
+    pub max: usize,
+    pub window: Duration,
+}
+
+/// Limits a server to a single shell session, as `tuihost --once` does.
+/// The first shell claims it and later ones are refused.
+#[derive(Debug, Default)]
+pub struct SingleSession {
+    claimed: AtomicBool,
+    started: Notify,
+    ended: Notify,
+}
+
+impl SingleSession {
+    /// Resolves once a shell has claimed the session.
+    pub async fn started(&self) {
+        self.started.notified().await
+    }
+
+    /// Resolves once the connection that claimed the session has closed.
+    pub async fn ended(&self) {
+        self.ended.notified().await
+    }
+
+    pub(crate) fn claim(&self) -> bool {
+        let claimed = !self.claimed.swap(true, Ordering::SeqCst);
+        if claimed {
+            self.started.notify_one();
+        }
+        claimed
+    }
+
+    pub(crate) fn end(&self) {
+        self.ended.notify_one();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    pub min_cols: u16,
+    pub max_cols: u16,
+    pub min_rows: u16,
+    pub max_rows: u16,
+    pub reject_small: bool,
+    /// Clear the client's screen before the MOTD and command
+    pub clear_on_start: bool,
+    /// Run the session on the terminal's alternate screen, restoring the
+    /// client's screen when it ends
+    pub alt_screen: bool,
+    /// TERM for the command instead of the one the client asked for
+    pub force_term: Option<String>,
+    /// Deliver signals sent by clients to their command's process group
+    pub forward_signals: bool,
+    /// Look up clients' hostnames, for logs and the command's
+    /// `TUIHOST_CLIENT_HOST`
+    pub reverse_dns: bool,
+    /// Drop clients whose address has no hostname; needs `reverse_dns`
+    pub require_reverse_dns: bool,
+    /// Refuse shells on channels without a PTY request
+    pub require_pty: bool,
+    pub allow_env: Vec<String>,
+    /// Users whose keystrokes are discarded; they only watch the output
+    pub readonly_users: Vec<String>,
+    /// Glob patterns (`*`, `?`) usernames must match; empty allows all
+    pub allowed_users: Vec<String>,
+    /// Later connections for a username watch the first one's command
+    /// instead of starting their own
+    pub shared_sessions: bool,
+    /// Splits `user+app` login names when `CommandMap::apps` isn't empty
+    pub app_separator: String,
+    /// Programs run when a session's command starts and when it ends
+    pub hooks: Option<Arc<SessionHooks>>,
+    /// Let one connection run a command on each of several channels;
+    /// otherwise a second shell request disconnects it
+    pub multi_shell: bool,
+    pub refresh_interval: Option<Duration>,
+    pub banner: Option<Arc<Banner>>,
+    pub motd: Option<Arc<Banner>>,
+    pub motd_delay: Option<Duration>,
+    pub max_ptys: usize,
+    /// Session channels one connection may have open at once (0 = unlimited)
+    pub max_channels: usize,
+    /// Bytes of output forwarded per command run before the session is closed (0 = unlimited)
+    pub max_output_bytes: u64,
+    /// Output forwarded per second before reads from the command pause (0 = unlimited)
+    pub max_output_bps: u64,
+    /// Longest command output is held to be sent in fewer, larger packets
+    /// (zero = sent as soon as it's read)
+    pub output_flush_interval: Duration,
+    /// Held output that's sent at once, without waiting out the interval
+    pub output_flush_bytes: usize,
+    /// Client input forwarded per second; excess is delayed, and a channel
+    /// that gets 256 KiB behind is closed (0 = unlimited)
+    pub max_input_bps: u64,
+    /// Shown to clients turned away by the connection limit
+    pub full_message: String,
+    /// Written to the channel when no terminal can be allocated for a
+    /// shell, before it's refused (empty = none)
+    pub pty_error_message: String,
+    /// How long client input may wait for the command to read its stdin
+    /// before it's dropped. Zero drops whatever doesn't fit immediately.
+    pub stdin_timeout: Duration,
+    /// Run before the session's command on the same terminal, sharing its
+    /// env, workdir and limits; only `command` and `args` are used
+    pub welcome_command: Option<CmdConfig>,
+    /// Typed into the command after `initial_input_delay`, also after a
+    /// respawn. Client input waits until it's written
+    pub initial_input: Option<Vec<u8>>,
+    pub initial_input_delay: Duration,
+    pub respawn: Option<RespawnPolicy>,
+    /// Receives one JSON line per connection
+    pub audit_log: Option<Arc<AuditLog>>,
+    /// Each connection writes its lifecycle events to a file of its own here
+    pub session_log_dir: Option<PathBuf>,
+    /// Development aid: dump up to this many bytes of everything read from
+    /// and written to the PTY at trace level, keystrokes and passwords
+    /// included. Never enable it in production.
+    pub debug_io: Option<usize>,
+    /// Honeypot mode: every login is refused and the credentials tried are
+    /// recorded here
+    pub honeypot: Option<Arc<HoneypotLog>>,
+    /// Methods the handler lets clients use. Keyboard-interactive asks for a
+    /// password; TOTP codes and honeypot mode use it whatever this says
+    pub auth_methods: MethodSet,
+    /// Users with a secret must also enter a TOTP code
+    pub totp: Option<Arc<TotpSecrets>>,
+    /// Only one shell session is run
+    pub single_session: Option<Arc<SingleSession>>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            min_cols: 10,
+            max_cols: 500,
+            min_rows: 5,
+            max_rows: 200,
+            reject_small: false,
+            clear_on_start: false,
+            alt_screen: false,
+            force_term: None,
+            forward_signals: false,
+            reverse_dns: false,
+            require_reverse_dns: false,
+            require_pty: true,
+            allow_env: Vec::new(),
+            readonly_users: Vec::new(),
+            allowed_users: Vec::new(),
+            shared_sessions: false,
+            app_separator: "+".to_string(),
+            hooks: None,
+            multi_shell: false,
+            refresh_interval: None,
+            banner: None,
+            motd: None,
+            motd_delay: None,
+            max_ptys: 0,
+            max_channels: 1,
+            max_output_bytes: 0,
+            max_output_bps: 0,
+            output_flush_interval: Duration::ZERO,
+            output_flush_bytes: 16384,
+            max_input_bps: 0,
+            full_message: DEFAULT_FULL_MESSAGE.to_string(),
+            pty_error_message: DEFAULT_PTY_ERROR_MESSAGE.to_string(),
+            stdin_timeout: Duration::from_secs(10),
+            welcome_command: None,
+            initial_input: None,
+            initial_input_delay: Duration::from_millis(200),
+            respawn: None,
+            audit_log: None,
+            session_log_dir: None,
+            debug_io: None,
+            honeypot: None,
+            auth_methods: MethodSet::from(
+                &[
+                    MethodKind::None,
+                    MethodKind::Password,
+                    MethodKind::PublicKey,
+                ][..],
+            ),
+            totp: None,
+            single_session: None,
+        }
+    }
 }
 
 pub struct TuiSshServer {
-    tui_config: Arc<CmdConfig>,
+    commands: Arc<CommandMap>,
+    session_config: Arc<SessionConfig>,
+    access_list: AccessList,
+    country_filter: Option<Arc<CountryFilter>>,
+    remote_allow: Option<Arc<RemoteAllowList>>,
+    ban_list: Option<Arc<BanList>>,
+    accept_rate: Option<AcceptRateLimiter>,
     max_connections: usize,
     active_connections: Arc<AtomicUsize>,
+    queue: Option<Arc<ConnectionQueue>>,
+    active_ptys: Arc<AtomicUsize>,
     max_session_duration: Option<Duration>,
+    handshake_timeout: Option<Duration>,
+    max_connection_age: Option<Duration>,
+    authenticator: Arc<dyn Authenticator>,
+    observer: Option<Arc<dyn EventObserver>>,
+    sessions: Arc<SessionRegistry>,
+    broadcaster: Broadcaster,
+    session_list: SessionList,
+    draining: Arc<AtomicBool>,
+    next_session_id: u64,
 }
 
 impl TuiSshServer {
-    pub fn new(
-        tui_config: CmdConfig,
-        max_connections: usize,
-        max_session_duration: Option<Duration>,
-    ) -> Self {
-        Self {
-            tui_config: Arc::new(tui_config),
-            max_connections,
-            active_connections: Arc::new(AtomicUsize::new(0)),
-            max_session_duration,
+    /// Sends notices to every live session, including ones that connect
+    /// after this is called.
+    pub fn broadcaster(&self) -> Broadcaster {
+        self.broadcaster.clone()
+    }
+
+    /// Connections currently holding a slot.
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::SeqCst)
+    }
+
+    /// Whether a new connection would be refused for lack of a slot.
+    pub fn is_full(&self) -> bool {
+        self.max_connections > 0 && self.active_connections() >= self.max_connections
+    }
+
+    pub fn connection_queue(&self) -> Option<Arc<ConnectionQueue>> {
+        self.queue.clone()
+    }
+
+    pub(crate) fn handshake_timeout(&self) -> Option<Duration> {
+        self.handshake_timeout
+    }
+
+    pub(crate) fn max_connection_age(&self) -> Option<Duration> {
+        self.max_connection_age
+    }
+
+    /// Live sessions, oldest first.
+    pub fn sessions(&self) -> Vec<ActiveSession> {
+        self.session_list.snapshot()
+    }
+
+    /// The live session list, for reading while the server runs.
+    pub fn session_list(&self) -> SessionList {
+        self.session_list.clone()
+    }
+
+    /// Resolves once every connection has closed and every command has
+    /// exited, e.g. to give sessions time to wind down after a shutdown.
+    pub fn idle(&self) -> impl Future<Output = ()> + Send + 'static {
+        let connections = self.active_connections.clone();
+        let ptys = self.active_ptys.clone();
+        async move {
+            while connections.load(Ordering::SeqCst) > 0 || ptys.load(Ordering::SeqCst) > 0 {
+                tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+            }
         }
     }
-}
 
-impl Server for TuiSshServer {
-    type Handler = SessionHandler;
+    /// While set, new connections are refused and existing ones are left
+    /// to finish, e.g. ahead of a restart.
+    pub fn draining(&self) -> Arc<AtomicBool> {
+        self.draining.clone()
+    }
 
-    fn new_client(&mut self, peer_addr: Option<std::net::SocketAddr>) -> Self::Handler {
+    pub fn builder() -> TuiSshServerBuilder {
+        TuiSshServerBuilder::default()
+    }
+
+    /// First half of [`new_client`](Server::new_client), for accept loops
+    /// that want to turn clients away before the SSH handshake: runs every
+    /// check that doesn't need a free slot (access list, country filter,
+    /// remote allow list, bans, accept rate, draining). A refused client
+    /// comes back as a rejected handler, whose
+    /// [`rejection`](SessionHandler::rejection) says why.
+    pub fn screen_client(
+        &mut self,
+        peer_addr: Option<SocketAddr>,
+    ) -> Result<SessionInfo, Box<SessionHandler>> {
         let addr_str = peer_addr
             .map(|a| a.to_string())
             .unwrap_or_else(|| "unknown".to_string());
+        let session = SessionInfo {
+            id: self.next_session_id,
+            addr: peer_addr,
+            user: None,
+            connected_at: SystemTime::now(),
+        };
+        self.next_session_id += 1;
+
+        if let Some(ip) = peer_addr.map(|a| a.ip())
+            && let Some(rule) = self.access_list.check(ip)
+        {
+            warn!("Denying connection from {} (matched {})", addr_str, rule);
+            return Err(Box::new(self.rejected_client(session, "access denied")));
+        }
+
+        if let Some(ip) = peer_addr.map(|a| a.ip())
+            && let Some(filter) = &self.country_filter
+            && let Some(reason) = filter.check(ip)
+        {
+            warn!("Denying connection from {} ({})", addr_str, reason);
+            return Err(Box::new(self.rejected_client(session, "access denied")));
+        }
+
+        if let Some(ip) = peer_addr.map(|a| a.ip())
+            && let Some(list) = &self.remote_allow
+            && let Some(reason) = list.check(ip)
+        {
+            warn!("Denying connection from {} ({})", addr_str, reason);
+            return Err(Box::new(self.rejected_client(session, "access denied")));
+        }
+
+        if let Some(ip) = peer_addr.map(|a| a.ip())
+            && let Some(ban_list) = &self.ban_list
+            && ban_list.is_banned(ip)
+        {
+            warn!("Denying connection from banned {}", addr_str);
+            return Err(Box::new(self.rejected_client(session, "banned")));
+        }
 
+        if let Some(accept_rate) = &mut self.accept_rate
+            && !accept_rate.try_accept()
+        {
+            debug!("Throttling connection from {}", addr_str);
+            return Err(Box::new(
+                self.rejected_client(session, "accept rate exceeded"),
+            ));
+        }
+
+        if self.draining.load(Ordering::SeqCst) {
+            info!("Refusing connection from {} while draining", addr_str);
+            return Err(Box::new(
+                self.rejected_client(session, "server is draining, try again later"),
+            ));
+        }
+
+        Ok(session)
+    }
+
+    /// Second half of [`new_client`](Server::new_client): takes a connection
+    /// slot for a screened client, or rejects it if the server is full.
+    pub fn admit_client(&mut self, session: SessionInfo) -> SessionHandler {
+        let addr_str = session
+            .addr
+            .map(|a| a.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
         let current = self.active_connections.fetch_add(1, Ordering::SeqCst);
 
         if self.max_connections > 0 && current >= self.max_connections {
@@ -51,22 +676,250 @@ impl Server for TuiSshServer {
                 current, self.max_connections, addr_str
             );
             self.active_connections.fetch_sub(1, Ordering::SeqCst);
-            // Return handler that will reject - russh doesn't have a direct reject mechanism
-            // The handler will still be created but connection limits are enforced at TCP level ideally
+            let message = self.session_config.full_message.clone();
+            return self.rejected_client(session, message);
         }
 
         info!("New connection from {} ({} active)", addr_str, current + 1);
+        if let Some(observer) = &self.observer {
+            observer.on_connect(&session);
+        }
+
+        let mut handler = self.client(session);
+        handler.track(self.session_list.clone());
+        handler
+    }
 
-        SessionHandler::new(
-            self.tui_config.clone(),
-            addr_str,
+    // russh has no way to refuse a connection from `new_client`, so the
+    // returned handler rejects every auth attempt instead. It doesn't hold
+    // a connection slot.
+    fn rejected_client(&self, session: SessionInfo, reason: impl Into<String>) -> SessionHandler {
+        let reason = reason.into();
+        if let Some(observer) = &self.observer {
+            observer.on_reject(&session, &reason);
+        }
+        let mut handler = self.client(session);
+        handler.reject(reason);
+        handler
+    }
+
+    fn client(&self, session: SessionInfo) -> SessionHandler {
+        let mut handler = SessionHandler::new(
+            self.commands.clone(),
+            self.session_config.clone(),
+            session,
             self.active_connections.clone(),
+            self.active_ptys.clone(),
             self.max_session_duration,
-        )
+            self.ban_list.clone(),
+        );
+        handler.set_authenticator(self.authenticator.clone());
+        handler.set_observer(self.observer.clone());
+        handler.set_registry(self.sessions.clone());
+        handler.set_broadcaster(self.broadcaster.clone());
+        handler.set_queue(self.queue.clone());
+        handler
+    }
+}
+
+/// Builds a [`TuiSshServer`]. Only a command is required; everything else
+/// defaults to what the CLI uses.
+pub struct TuiSshServerBuilder {
+    commands: CommandMap,
+    session_config: SessionConfig,
+    access_list: AccessList,
+    country_filter: Option<CountryFilter>,
+    remote_allow: Option<Arc<RemoteAllowList>>,
+    ban_list: Option<BanList>,
+    accept_rate: Option<AcceptRateLimiter>,
+    max_connections: usize,
+    queue: Option<Arc<ConnectionQueue>>,
+    max_session_duration: Option<Duration>,
+    handshake_timeout: Option<Duration>,
+    max_connection_age: Option<Duration>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    observer: Option<Arc<dyn EventObserver>>,
+}
+
+impl Default for TuiSshServerBuilder {
+    fn default() -> Self {
+        Self {
+            commands: CommandMap {
+                default: None,
+                users: HashMap::new(),
+                user_env: HashMap::new(),
+                apps: HashMap::new(),
+            },
+            session_config: SessionConfig::default(),
+            access_list: AccessList::default(),
+            country_filter: None,
+            remote_allow: None,
+            ban_list: None,
+            accept_rate: None,
+            max_connections: 100,
+            queue: None,
+            max_session_duration: None,
+            handshake_timeout: None,
+            max_connection_age: None,
+            authenticator: None,
+            observer: None,
+        }
+    }
+}
+
+impl TuiSshServerBuilder {
+    /// Command for users without their own entry.
+    pub fn command(mut self, command: CmdConfig) -> Self {
+        self.commands.default = Some(command);
+        self
+    }
+
+    pub fn user_command(mut self, user: impl Into<String>, command: CmdConfig) -> Self {
+        self.commands.users.insert(user.into(), command);
+        self
+    }
+
+    /// Replaces every command set so far.
+    pub fn commands(mut self, commands: CommandMap) -> Self {
+        self.commands = commands;
+        self
+    }
+
+    pub fn session_config(mut self, session_config: SessionConfig) -> Self {
+        self.session_config = session_config;
+        self
+    }
+
+    pub fn access_list(mut self, access_list: AccessList) -> Self {
+        self.access_list = access_list;
+        self
+    }
+
+    /// Refuses connections by country, checked after the access list.
+    pub fn country_filter(mut self, filter: CountryFilter) -> Self {
+        self.country_filter = Some(filter);
+        self
+    }
+
+    /// Only admits addresses on `list`, checked after the country filter.
+    /// Shared so the caller can keep refreshing it.
+    pub fn remote_allow_list(mut self, list: Arc<RemoteAllowList>) -> Self {
+        self.remote_allow = Some(list);
+        self
+    }
+
+    pub fn ban_list(mut self, ban_list: BanList) -> Self {
+        self.ban_list = Some(ban_list);
+        self
+    }
+
+    pub fn accept_rate(mut self, accept_rate: AcceptRateLimiter) -> Self {
+        self.accept_rate = Some(accept_rate);
+        self
+    }
+
+    /// 0 means unlimited.
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Lets connections wait for a slot while the server is full. Only
+    /// [`serve`](crate::serve) waits on the queue; russh's `run_on_socket`
+    /// rejects a full server's clients as before.
+    pub fn connection_queue(mut self, queue: Arc<ConnectionQueue>) -> Self {
+        self.queue = Some(queue);
+        self
+    }
+
+    /// Longest a session may last regardless of activity.
+    pub fn session_duration(mut self, duration: Duration) -> Self {
+        self.max_session_duration = Some(duration);
+        self
+    }
+
+    /// Drops connections that haven't logged in within `timeout`. Applied
+    /// by [`serve`](crate::serve).
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Disconnects connections older than `age`, logged in or not. Applied
+    /// by [`serve`](crate::serve).
+    pub fn max_connection_age(mut self, age: Duration) -> Self {
+        self.max_connection_age = Some(age);
+        self
+    }
+
+    /// Decides who may log in. Everyone is let in by default.
+    pub fn authenticator(mut self, authenticator: impl Authenticator + 'static) -> Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Receives connection lifecycle events.
+    pub fn observer(mut self, observer: impl EventObserver + 'static) -> Self {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    pub fn build(self) -> Result<TuiSshServer> {
+        anyhow::ensure!(
+            self.commands.default.is_some()
+                || !self.commands.users.is_empty()
+                || !self.commands.apps.is_empty(),
+            "TuiSshServer needs a default, per-user or per-app command"
+        );
+        let config = &self.session_config;
+        anyhow::ensure!(
+            config.min_cols <= config.max_cols && config.min_rows <= config.max_rows,
+            "Minimum PTY size must not exceed the maximum"
+        );
+        Ok(TuiSshServer {
+            commands: Arc::new(self.commands),
+            session_config: Arc::new(self.session_config),
+            access_list: self.access_list,
+            country_filter: self.country_filter.map(Arc::new),
+            remote_allow: self.remote_allow,
+            ban_list: self.ban_list.map(Arc::new),
+            accept_rate: self.accept_rate,
+            max_connections: self.max_connections,
+            active_connections: Arc::new(AtomicUsize::new(0)),
+            queue: self.queue,
+            active_ptys: Arc::new(AtomicUsize::new(0)),
+            max_session_duration: self.max_session_duration,
+            handshake_timeout: self.handshake_timeout,
+            max_connection_age: self.max_connection_age,
+            authenticator: self
+                .authenticator
+                .unwrap_or_else(|| Arc::new(AllowAllAuthenticator)),
+            observer: self.observer,
+            sessions: Arc::default(),
+            broadcaster: Broadcaster::default(),
+            session_list: SessionList::default(),
+            draining: Arc::default(),
+            next_session_id: 1,
+        })
     }
 }
 
-pub fn create_config(host_key: PrivateKey, timeout_secs: u64) -> Config {
+impl Server for TuiSshServer {
+    type Handler = SessionHandler;
+
+    fn new_client(&mut self, peer_addr: Option<SocketAddr>) -> Self::Handler {
+        match self.screen_client(peer_addr) {
+            Ok(session) => self.admit_client(session),
+            Err(handler) => *handler,
+        }
+    }
+}
+
+pub fn create_config(
+    host_keys: Vec<PrivateKey>,
+    timeout_secs: u64,
+    algorithms: &Algorithms,
+) -> Config {
     let timeout = if timeout_secs > 0 {
         Some(Duration::from_secs(timeout_secs))
     } else {
@@ -74,7 +927,8 @@ pub fn create_config(host_key: PrivateKey, timeout_secs: u64) -> Config {
     };
 
     Config {
-        keys: vec![host_key],
+        keys: host_keys,
+        preferred: algorithms.preferred(),
         inactivity_timeout: timeout,
         auth_rejection_time: Duration::from_secs(1),
         auth_rejection_time_initial: Some(Duration::from_secs(0)),
@@ -82,6 +936,261 @@ pub fn create_config(host_key: PrivateKey, timeout_secs: u64) -> Config {
     }
 }
 
-pub fn generate_host_key() -> PrivateKey {
-    PrivateKey::random(&mut rand_core::OsRng, Algorithm::Ed25519).expect("Failed to generate key")
+/// Generates a host key. Only Ed25519 and RSA (3072-bit) are supported.
+pub fn generate_host_key(algorithm: Algorithm) -> Result<PrivateKey> {
+    let key = match algorithm {
+        Algorithm::Ed25519 => PrivateKey::random(&mut rand_core::OsRng, Algorithm::Ed25519)?,
+        Algorithm::Rsa { .. } => {
+            let keypair = RsaKeypair::random(&mut rand_core::OsRng, RSA_HOST_KEY_BITS)?;
+            PrivateKey::new(KeypairData::from(keypair), "")?
+        }
+        other => anyhow::bail!("Unsupported host key algorithm: {}", other),
+    };
+    Ok(key)
+}
+
+/// Algorithm to generate for a missing key file: RSA if the file name
+/// mentions "rsa" (e.g. `host_key_rsa`), Ed25519 otherwise.
+fn host_key_algorithm(path: &Path) -> Algorithm {
+    let is_rsa = path
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().to_lowercase().contains("rsa"));
+    if is_rsa {
+        Algorithm::Rsa { hash: None }
+    } else {
+        Algorithm::Ed25519
+    }
+}
+
+/// Loads an OpenSSH private key from `path`; fails if it's missing.
+pub fn load_host_key(path: &str) -> Result<PrivateKey> {
+    info!("Loading host key from: {}", path);
+    let key_data = std::fs::read_to_string(path).context("Failed to read host key file")?;
+    key_data
+        .parse::<PrivateKey>()
+        .map_err(|e| anyhow::anyhow!("Failed to parse host key {}: {}", path, e))
+}
+
+/// Loads an OpenSSH private key from `path`, generating and saving a new
+/// key (mode 600) if the file doesn't exist. Missing keys whose file name
+/// contains "rsa" are generated as RSA, others as Ed25519.
+pub fn load_or_generate_host_key(path: &str) -> Result<PrivateKey> {
+    let key_path = Path::new(path);
+
+    if key_path.exists() {
+        load_host_key(path)
+    } else {
+        let algorithm = host_key_algorithm(key_path);
+        warn!(
+            "Host key not found, generating new {} key at: {}",
+            algorithm, path
+        );
+        let key = generate_host_key(algorithm)?;
+
+        let openssh_key = key
+            .to_openssh(ssh_key::LineEnding::LF)
+            .map_err(|e| anyhow::anyhow!("Failed to encode key: {}", e))?;
+        std::fs::write(key_path, openssh_key.as_bytes())
+            .context("Failed to write host key file")?;
+
+        // Set secure permissions (600)
+        std::fs::set_permissions(key_path, std::fs::Permissions::from_mode(0o600))
+            .context("Failed to set host key permissions")?;
+
+        info!("Generated and saved new host key");
+        Ok(key)
+    }
+}
+
+/// Loads or generates every key in `paths`, for serving several algorithms.
+pub fn load_or_generate_host_keys(paths: &[String]) -> Result<Vec<PrivateKey>> {
+    paths
+        .iter()
+        .map(|path| load_or_generate_host_key(path))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(addr: &str) -> Option<SocketAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    fn server() -> TuiSshServerBuilder {
+        TuiSshServer::builder().command(CmdConfig::new("true"))
+    }
+
+    fn commands() -> CommandMap {
+        CommandMap {
+            default: Some(CmdConfig::new("default")),
+            users: HashMap::from([("alice".to_string(), CmdConfig::new("alice-cmd"))]),
+            user_env: HashMap::new(),
+            apps: HashMap::from([("top".to_string(), CmdConfig::new("htop"))]),
+        }
+    }
+
+    #[test]
+    fn users_without_an_entry_get_the_default_command() {
+        let commands = commands();
+        assert_eq!(commands.for_user("alice").unwrap().command, "alice-cmd");
+        assert_eq!(commands.for_user("bob").unwrap().command, "default");
+    }
+
+    #[test]
+    fn without_a_default_only_listed_users_get_a_command() {
+        let commands = CommandMap {
+            default: None,
+            ..commands()
+        };
+        assert!(commands.for_user("alice").is_some());
+        assert!(commands.for_user("bob").is_none());
+    }
+
+    #[test]
+    fn commands_are_looked_up_in_path() {
+        let path = CmdConfig::new("sh").resolve().unwrap();
+        assert!(
+            path.is_absolute() && path.ends_with("sh"),
+            "{}",
+            path.display()
+        );
+    }
+
+    #[test]
+    fn missing_commands_are_rejected() {
+        let err = CmdConfig::new("no-such-command-tuihost")
+            .resolve()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Command not found in PATH: no-such-command-tuihost"
+        );
+        let err = CmdConfig::new("/nonexistent/tuihost")
+            .resolve()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Command not found: /nonexistent/tuihost");
+    }
+
+    #[test]
+    fn the_path_override_is_searched_instead() {
+        let mut config = CmdConfig::new("sh");
+        config
+            .env
+            .push(("PATH".to_string(), "/nonexistent".to_string()));
+        assert!(config.resolve().is_err());
+    }
+
+    #[test]
+    fn files_without_an_exec_bit_are_rejected() {
+        let path = std::env::temp_dir().join(format!("tuihost-noexec-{}", std::process::id()));
+        std::fs::write(&path, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let err = CmdConfig::new(path.to_str().unwrap())
+            .resolve()
+            .unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!("Command is not executable: {}", path.display())
+        );
+    }
+
+    #[test]
+    fn validation_names_the_user_with_a_missing_command() {
+        let mut commands = commands();
+        commands.default = Some(CmdConfig::new("sh"));
+        commands.apps.clear();
+        commands.users.insert(
+            "alice".to_string(),
+            CmdConfig::new("no-such-command-tuihost"),
+        );
+        let err = commands.validate().unwrap_err();
+        assert_eq!(err.to_string(), "Invalid command for user alice");
+    }
+
+    #[test]
+    fn a_picked_app_wins_over_the_user_command() {
+        let commands = commands();
+        assert_eq!(
+            commands.for_session("alice", Some("top")).unwrap().command,
+            "htop"
+        );
+        assert_eq!(
+            commands
+                .for_session("alice", Some("unknown"))
+                .unwrap()
+                .command,
+            "alice-cmd"
+        );
+        assert_eq!(
+            commands.for_session("bob", None).unwrap().command,
+            "default"
+        );
+    }
+
+    #[test]
+    fn banned_peers_are_refused_before_taking_a_slot() {
+        let mut server = server()
+            .ban_list(BanList::new(
+                1,
+                Duration::from_secs(60),
+                Duration::from_secs(60),
+            ))
+            .max_connections(1)
+            .build()
+            .unwrap();
+        let banned = peer("192.0.2.1:40000").unwrap();
+        server
+            .ban_list
+            .as_ref()
+            .unwrap()
+            .record_failure(banned.ip());
+
+        let refused = server.screen_client(Some(banned)).err().unwrap();
+        assert_eq!(refused.rejection(), Some("banned"));
+        assert_eq!(server.active_connections(), 0);
+
+        let session = server.screen_client(peer("192.0.2.2:40000")).ok().unwrap();
+        let handler = server.admit_client(session);
+        assert_eq!(handler.rejection(), None);
+        assert_eq!(server.active_connections(), 1);
+    }
+
+    #[test]
+    fn throttled_peers_are_refused_at_screening() {
+        let mut server = server()
+            .accept_rate(AcceptRateLimiter::new(1))
+            .build()
+            .unwrap();
+        assert!(server.screen_client(peer("192.0.2.1:40000")).is_ok());
+        let refused = server.screen_client(peer("192.0.2.1:40001")).err().unwrap();
+        assert_eq!(refused.rejection(), Some("accept rate exceeded"));
+    }
+
+    #[test]
+    fn full_server_rejects_on_admission() {
+        let mut server = server().max_connections(1).build().unwrap();
+        let first = server.screen_client(peer("192.0.2.1:40000")).ok().unwrap();
+        let _first = server.admit_client(first);
+        let second = server.screen_client(peer("192.0.2.1:40001")).ok().unwrap();
+        let second = server.admit_client(second);
+        assert_eq!(second.rejection(), Some(DEFAULT_FULL_MESSAGE));
+        assert_eq!(server.active_connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn single_session_goes_to_the_first_claim() {
+        let single = SingleSession::default();
+        assert!(single.claim());
+        assert!(!single.claim());
+        tokio::time::timeout(Duration::from_secs(1), single.started())
+            .await
+            .unwrap();
+        single.end();
+        tokio::time::timeout(Duration::from_secs(1), single.ended())
+            .await
+            .unwrap();
+    }
 }
@@ -0,0 +1,47 @@
+use anyhow::{Context, Result};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// One connection's lifecycle events, in a file of its own so a single
+/// session can be followed without searching the server log. Closed when
+/// the last holder drops it.
+#[derive(Debug)]
+pub(crate) struct SessionLog {
+    file: Mutex<File>,
+}
+
+impl SessionLog {
+    /// Creates `session-<id>-<unix time>.log` in `dir`; the time keeps ids
+    /// reused after a restart from sharing a file.
+    pub(crate) fn create(dir: &Path, session_id: u64) -> Result<Self> {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let path = dir.join(format!("session-{}-{}.log", session_id, started));
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open session log {}", path.display()))?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Appends one line, prefixed with unix time in milliseconds.
+    pub(crate) fn write(&self, event: fmt::Arguments) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = format!("{}.{:03} {}\n", now.as_secs(), now.subsec_millis(), event);
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("Failed to write session log: {}", e);
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use crate::events::SessionInfo;
+use crate::handler::ByteCounts;
+use russh::Disconnect;
+use russh::server::Handle;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+// Shown to clients disconnected through SessionList::kill
+const KILL_MESSAGE: &str = "disconnected by the administrator";
+
+/// The connections currently holding a slot, for embedders and admin tools.
+/// Cheap to clone; every clone sees the same sessions.
+#[derive(Debug, Clone, Default)]
+pub struct SessionList {
+    sessions: Arc<Mutex<HashMap<u64, Tracked>>>,
+}
+
+#[derive(Debug)]
+struct Tracked {
+    info: SessionInfo,
+    bytes: Arc<ByteCounts>,
+    kill: Arc<Notify>,
+    // Known once the client opens a channel
+    handle: Option<Handle>,
+    // Set by the first kill, while the connection is still winding down
+    killed: bool,
+}
+
+/// What [`SessionList::kill`] found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KillOutcome {
+    Killed,
+    /// No session has that id
+    NotFound,
+    /// The session ended, or was already killed and is winding down, by
+    /// the time it was to be disconnected
+    AlreadyGone,
+}
+
+/// One live connection as of [`SessionList::snapshot`].
+#[derive(Debug, Clone)]
+pub struct ActiveSession {
+    pub info: SessionInfo,
+    /// Bytes received from the client
+    pub bytes_in: u64,
+    /// Bytes of command output sent to the client
+    pub bytes_out: u64,
+}
+
+impl SessionList {
+    pub(crate) fn insert(&self, info: SessionInfo, bytes: Arc<ByteCounts>, kill: Arc<Notify>) {
+        self.sessions.lock().unwrap().insert(
+            info.id,
+            Tracked {
+                info,
+                bytes,
+                kill,
+                handle: None,
+                killed: false,
+            },
+        );
+    }
+
+    pub(crate) fn set_handle(&self, session_id: u64, handle: Handle) {
+        if let Some(tracked) = self.sessions.lock().unwrap().get_mut(&session_id) {
+            tracked.handle = Some(handle);
+        }
+    }
+
+    pub(crate) fn set_user(&self, session_id: u64, user: &str) {
+        if let Some(tracked) = self.sessions.lock().unwrap().get_mut(&session_id) {
+            tracked.info.user = Some(user.to_string());
+        }
+    }
+
+    pub(crate) fn remove(&self, session_id: u64) {
+        self.sessions.lock().unwrap().remove(&session_id);
+    }
+
+    /// Disconnects `session_id`, which terminates its commands as with any
+    /// other disconnect. Connections that haven't opened a channel yet are
+    /// left to their accept loop to drop.
+    pub async fn kill(&self, session_id: u64) -> KillOutcome {
+        let (handle, kill) = match self.sessions.lock().unwrap().get_mut(&session_id) {
+            Some(tracked) if tracked.killed => return KillOutcome::AlreadyGone,
+            Some(tracked) => {
+                tracked.killed = true;
+                (tracked.handle.clone(), tracked.kill.clone())
+            }
+            None => return KillOutcome::NotFound,
+        };
+        let Some(handle) = handle else {
+            kill.notify_one();
+            return KillOutcome::Killed;
+        };
+        let disconnect = handle.disconnect(
+            Disconnect::ByApplication,
+            KILL_MESSAGE.to_string(),
+            "en".to_string(),
+        );
+        match disconnect.await {
+            Ok(()) => KillOutcome::Killed,
+            // The session task is gone and took its end of the handle along
+            Err(_) => KillOutcome::AlreadyGone,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Live sessions, oldest first.
+    pub fn snapshot(&self) -> Vec<ActiveSession> {
+        let mut sessions: Vec<_> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .values()
+            .map(|tracked| ActiveSession {
+                info: tracked.info.clone(),
+                bytes_in: tracked.bytes.input.load(Ordering::Relaxed),
+                bytes_out: tracked.bytes.output.load(Ordering::Relaxed),
+            })
+            .collect();
+        sessions.sort_by_key(|session| session.info.id);
+        sessions
+    }
+}
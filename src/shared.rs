@@ -0,0 +1,175 @@
+use crate::pty::PtyWriter;
+use russh::server::Handle;
+use russh::{ChannelId, CryptoVec};
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+use tokio::sync::{Mutex, mpsc};
+use tracing::{debug, warn};
+
+// Output chunks queued for a viewer before it's detached as too slow
+const VIEWER_QUEUE: usize = 256;
+
+/// Running shared sessions by name (the SSH username).
+#[derive(Debug, Default)]
+pub(crate) struct SessionRegistry {
+    sessions: std::sync::Mutex<HashMap<String, Arc<SharedSession>>>,
+}
+
+impl SessionRegistry {
+    pub(crate) fn get(&self, name: &str) -> Option<Arc<SharedSession>> {
+        self.sessions.lock().unwrap().get(name).cloned()
+    }
+
+    /// False if another session already runs under `name`.
+    pub(crate) fn insert(&self, name: &str, session: Arc<SharedSession>) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.contains_key(name) {
+            return false;
+        }
+        sessions.insert(name.to_string(), session);
+        true
+    }
+
+    pub(crate) fn remove(&self, name: &str, session: &Arc<SharedSession>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions
+            .get(name)
+            .is_some_and(|current| Arc::ptr_eq(current, session))
+        {
+            sessions.remove(name);
+        }
+    }
+}
+
+/// A command driven by one connection and watched by others. The PTY is
+/// kept at the smallest window among everyone attached.
+#[derive(Debug)]
+pub(crate) struct SharedSession {
+    driver: u64,
+    writer: Weak<Mutex<PtyWriter>>,
+    state: std::sync::Mutex<SharedState>,
+}
+
+#[derive(Debug)]
+struct SharedState {
+    driver_size: (u16, u16),
+    viewers: HashMap<u64, Viewer>,
+}
+
+#[derive(Debug)]
+struct Viewer {
+    output: mpsc::Sender<CryptoVec>,
+    size: (u16, u16),
+}
+
+impl SharedSession {
+    pub(crate) fn new(driver: u64, writer: Weak<Mutex<PtyWriter>>, size: (u16, u16)) -> Self {
+        Self {
+            driver,
+            writer,
+            state: std::sync::Mutex::new(SharedState {
+                driver_size: size,
+                viewers: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Starts copying output to the viewer's channel, closing it once the
+    /// viewer is detached or the session ends.
+    pub(crate) async fn attach(
+        &self,
+        id: u64,
+        handle: Handle,
+        channel: ChannelId,
+        size: (u16, u16),
+    ) {
+        let (output, mut queued) = mpsc::channel::<CryptoVec>(VIEWER_QUEUE);
+        tokio::spawn(async move {
+            while let Some(data) = queued.recv().await {
+                if handle.data(channel, data).await.is_err() {
+                    return;
+                }
+            }
+            let _ = handle.close(channel).await;
+        });
+        self.state
+            .lock()
+            .unwrap()
+            .viewers
+            .insert(id, Viewer { output, size });
+        self.apply_size().await;
+
+        // Redraw so the new viewer doesn't start on a blank screen
+        if let Some(writer) = self.writer.upgrade()
+            && let Err(e) = writer.lock().await.refresh()
+        {
+            debug!("Failed to refresh shared session: {}", e);
+        }
+    }
+
+    pub(crate) fn detach(self: &Arc<Self>, id: u64) {
+        if self.state.lock().unwrap().viewers.remove(&id).is_some() {
+            let session = self.clone();
+            tokio::spawn(async move { session.apply_size().await });
+        }
+    }
+
+    /// Records a window change from the driver or a viewer.
+    pub(crate) async fn resize(&self, id: u64, size: (u16, u16)) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if id == self.driver {
+                state.driver_size = size;
+            } else if let Some(viewer) = state.viewers.get_mut(&id) {
+                viewer.size = size;
+            }
+        }
+        self.apply_size().await;
+    }
+
+    /// Copies output to every viewer. Viewers that can't keep up are
+    /// detached rather than stalling the driver.
+    pub(crate) fn broadcast(&self, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        state.viewers.retain(|id, viewer| {
+            match viewer.output.try_send(CryptoVec::from_slice(data)) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    warn!(
+                        "Viewer {} can't keep up with the shared session, detaching",
+                        id
+                    );
+                    false
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            }
+        });
+    }
+
+    /// Sends `message` to every viewer and closes their channels.
+    pub(crate) fn close(&self, message: &str) {
+        self.broadcast(message.as_bytes());
+        self.state.lock().unwrap().viewers.clear();
+    }
+
+    async fn apply_size(&self) {
+        let size = {
+            let state = self.state.lock().unwrap();
+            state
+                .viewers
+                .values()
+                .fold(state.driver_size, |(cols, rows), viewer| {
+                    (cols.min(viewer.size.0), rows.min(viewer.size.1))
+                })
+        };
+        let Some(writer) = self.writer.upgrade() else {
+            return;
+        };
+        let mut writer = writer.lock().await;
+        if writer.size() != size
+            && let Err(e) = writer.resize(size.0, size.1)
+        {
+            warn!("Failed to resize shared session: {}", e);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+// Runs a server on a local port and drives it with russh's client, for
+// tests that need a whole session rather than one handler call
+
+use crate::algorithms::Algorithms;
+use crate::serve::serve;
+use crate::server::{TuiSshServer, create_config, generate_host_key};
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+use russh::client::{self, Handle};
+use russh::keys::{Algorithm, PublicKey};
+use russh::{Channel, ChannelMsg};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+
+// Longest any single step of a test may take before it fails
+const STEP_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) struct TestServer {
+    addr: SocketAddr,
+    shutdown: broadcast::Sender<String>,
+}
+
+impl TestServer {
+    pub(crate) async fn start(server: TuiSshServer) -> Self {
+        let host_key = generate_host_key(Algorithm::Ed25519).unwrap();
+        let config = create_config(vec![host_key], 0, &Algorithms::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown, _) = broadcast::channel(1);
+        tokio::spawn(serve(
+            Arc::new(Mutex::new(server)),
+            Arc::new(config),
+            listener,
+            shutdown.clone(),
+        ));
+        Self { addr, shutdown }
+    }
+
+    /// Logs in as `user` and requests a terminal, stopping short of the shell.
+    pub(crate) async fn open(&self, user: &str, cols: u32, rows: u32) -> Shell {
+        let config = Arc::new(client::Config::default());
+        let mut handle = client::connect(config, self.addr, Client).await.unwrap();
+        let auth = handle.authenticate_none(user).await.unwrap();
+        assert!(auth.success(), "login as {} refused", user);
+        let channel = handle.channel_open_session().await.unwrap();
+        channel
+            .request_pty(false, "xterm", cols, rows, 0, 0, &[])
+            .await
+            .unwrap();
+        Shell {
+            _handle: handle,
+            channel,
+            output: Vec::new(),
+            closed: false,
+        }
+    }
+
+    /// Logs in as `user` and starts the shell on an 80x24 terminal.
+    pub(crate) async fn shell(&self, user: &str) -> Shell {
+        let shell = self.open(user, 80, 24).await;
+        shell.start().await;
+        shell
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.shutdown.send("test over".to_string());
+    }
+}
+
+pub(crate) struct Client;
+
+impl client::Handler for Client {
+    type Error = russh::Error;
+
+    async fn check_server_key(&mut self, _key: &PublicKey) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+}
+
+pub(crate) struct Shell {
+    // Dropping it drops the connection
+    _handle: Handle<Client>,
+    pub(crate) channel: Channel<client::Msg>,
+    /// Everything the server has sent on the channel so far
+    pub(crate) output: Vec<u8>,
+    closed: bool,
+}
+
+impl Shell {
+    pub(crate) async fn start(&self) {
+        self.channel.request_shell(false).await.unwrap();
+    }
+
+    pub(crate) fn text(&self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+
+    /// Reads the `pid=<pid>` line a test command prints on startup.
+    pub(crate) async fn read_pid(&mut self) -> i32 {
+        loop {
+            let text = self.text();
+            if let Some((_, rest)) = text.split_once("pid=")
+                && let Some((pid, _)) = rest.split_once('\n')
+            {
+                return pid.trim().parse().unwrap();
+            }
+            assert!(self.read().await, "channel closed before the pid");
+        }
+    }
+
+    /// Reads until the output contains `needle`.
+    pub(crate) async fn read_until(&mut self, needle: &str) {
+        while !self.text().contains(needle) {
+            assert!(self.read().await, "channel closed before {:?}", needle);
+        }
+    }
+
+    /// Reads until the server closes the channel or the connection.
+    pub(crate) async fn read_to_end(&mut self) {
+        while self.read().await {}
+    }
+
+    // Reads one message, returning false once the channel is gone
+    async fn read(&mut self) -> bool {
+        if self.closed {
+            return false;
+        }
+        let msg = tokio::time::timeout(STEP_TIMEOUT, self.channel.wait())
+            .await
+            .expect("timed out waiting for the server");
+        match msg {
+            Some(ChannelMsg::Data { data }) => self.output.extend_from_slice(&data),
+            Some(ChannelMsg::Close) | None => self.closed = true,
+            Some(_) => {}
+        }
+        !self.closed
+    }
+}
+
+/// Whether `pid` is gone for good, i.e. exited and reaped, within `timeout`.
+/// A zombie still counts as present.
+pub(crate) async fn reaped_within(pid: i32, timeout: Duration) -> bool {
+    let gone = async {
+        while kill(Pid::from_raw(pid), None) != Err(Errno::ESRCH) {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    };
+    tokio::time::timeout(timeout, gone).await.is_ok()
+}